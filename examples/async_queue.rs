@@ -0,0 +1,35 @@
+//! The async handle, several tasks per rank use the queue at once while the driver runs next to
+//! them on the thread that owns MPI. Run with `mpiexec -n 3 ./target/debug/examples/async_queue`
+use std::error::Error;
+use async_queue_algorithm::AsyncQueue;
+use mpi::traits::*;
+use tokio::task::{ self, LocalSet };
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let universe = mpi::initialize().ok_or("MPI was already initialized")?;
+    let world = universe.world();
+    let rank = world.rank();
+    let (queue, driver) = AsyncQueue::<u64>::new(&world)?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build()?;
+    LocalSet::new().block_on(&runtime, async move {
+        let driver = task::spawn_local(driver.run());
+        let workers: Vec<_> = (0..4).map(|worker| {
+            let queue = queue.clone();
+            task::spawn(async move {
+                queue.enqueue(rank as u64 * 100 + worker).await?;
+                queue.dequeue().await
+            })
+        }).collect();
+        drop(queue); // the driver shuts down once the last handle is gone
+
+        for worker in workers {
+            println!("rank {} took {:?}", rank, worker.await??);
+        }
+        driver.await??;
+        Ok::<_, Box<dyn Error>>(())
+    })?;
+
+    world.barrier();
+    Ok(())
+}
@@ -0,0 +1,24 @@
+//! Embedding the queue in an MPI program, run with `mpiexec -n 3 ./target/debug/examples/embed`.
+//! Every rank enqueues two items and takes two, the queue has its own communicator so the
+//! program can keep using the world for its own messages
+use std::error::Error;
+use async_queue_algorithm::DistributedQueue;
+use mpi::traits::*;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let universe = mpi::initialize().ok_or("MPI was already initialized")?;
+    let world = universe.world();
+    let rank = world.rank();
+
+    let mut queue = DistributedQueue::<u64>::new(&world)?;
+    queue.enqueue(rank as u64 * 10)?;
+    queue.enqueue(rank as u64 * 10 + 1)?;
+    let first = queue.dequeue()?;
+    let second = queue.dequeue()?;
+    println!("rank {} took {:?} and {:?}", rank, first, second);
+
+    // every rank has to get here, the others may still need our answers until then
+    queue.shutdown()?;
+    world.barrier();
+    Ok(())
+}
@@ -0,0 +1,24 @@
+//! Two independent queues in one job, even ranks share one and odd ranks the other. Run with
+//! `mpiexec -n 4 ./target/debug/examples/split_queues`
+use std::error::Error;
+use async_queue_algorithm::DistributedQueue;
+use mpi::topology::Color;
+use mpi::traits::*;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let universe = mpi::initialize().ok_or("MPI was already initialized")?;
+    let world = universe.world();
+    let rank = world.rank();
+
+    let half = world.split_by_color(Color::with_value(rank % 2))
+        .ok_or("every rank has a color, so every rank gets a half")?;
+    let mut queue = DistributedQueue::<String>::new(&half)?;
+    queue.enqueue(format!("from world rank {}", rank))?;
+    let got = queue.dequeue()?;
+    println!("world rank {} (rank {} of the {} queue) took {:?}",
+             rank, queue.rank(), if rank % 2 == 0 { "even" } else { "odd" }, got);
+    queue.shutdown()?;
+
+    world.barrier(); // the world is still free for the program's own use
+    Ok(())
+}
@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::time::{ Duration, Instant };
+use mpi::Rank;
+use mpi::traits::*;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{ mpsc, oneshot };
+use tokio::{ task, time };
+use crate::op_handle::{ dequeued, dequeued_many, enqueued };
+use crate::queue::join;
+use crate::stream::{ QueueSink, QueueStream };
+use crate::util::error::QueueError;
+use crate::util::history::{ QueueCall, QueueResult };
+use crate::util::message_structs::{ OpId, QueueId, DEFAULT_QUEUE };
+use crate::util::payload::Payload;
+use crate::util::process::Process;
+use crate::util::transport::{ MpiTransport, Transport };
+
+/// How long an idle driver waits before it polls the transport again, doubling up to IDLE_MAX
+/// while nothing happens
+const IDLE_MIN: Duration = Duration::from_micros(50);
+const IDLE_MAX: Duration = Duration::from_millis(2);
+
+pub(crate) type Reply<R> = oneshot::Sender<Result<R, QueueError>>;
+
+/// What a handle asks the driver for
+pub(crate) enum Request<T: Payload> {
+    Open { name: String, reply: Reply<QueueId> },
+    Invoke { queue: QueueId, call: QueueCall<T>, reply: Reply<QueueResult<T>> },
+    Take { queue: QueueId, timeout: Option<Duration>, reply: Reply<Option<T>> },
+    DrainCancelled { reply: Reply<Vec<T>> },
+}
+
+/// A take that is either dequeuing or parked until another item arrives, see Process::take
+struct Take<T: Payload> {
+    queue: QueueId,
+    arrived: usize, // items the local queue had seen when the last attempt went out
+    deadline: Option<Instant>,
+    reply: Reply<Option<T>>,
+}
+
+/// Who gets told when one of our ops finishes
+enum Waiter<T: Payload> {
+    Op(Reply<QueueResult<T>>),
+    Take(Take<T>),
+}
+
+/// Async handle on this rank's replica of the queue, the same queue DistributedQueue gives
+/// but nothing blocks. Clones are cheap and Send, so any task can have one, and ops from
+/// different tasks are in flight at the same time. The work happens in the Driver that comes
+/// with it
+#[derive(Clone)]
+pub struct AsyncQueue<T: Payload> {
+    requests: mpsc::UnboundedSender<Request<T>>,
+    rank: Rank,
+    size: usize,
+}
+
+/// Owns the rank's Process: invokes what the handles ask for, answers the other ranks and
+/// completes the handles' futures when acks or SAFE/UNSAFE come in. MPI communicators stay on
+/// the thread that made them, so run it there, eg with LocalSet::spawn_local. When idle it
+/// sleeps between polls, so the runtime needs its timers enabled
+pub struct Driver<T: Payload, C: Transport<T>> {
+    process: Process<T, C>,
+    requests: mpsc::UnboundedReceiver<Request<T>>,
+    waiting: HashMap<OpId, Waiter<T>>, // ops in flight and who to tell when they finish
+    parked: Vec<Take<T>>, // takes that found the queue empty
+}
+
+impl<T: Payload> AsyncQueue<T> {
+    /// Joins the queue on every rank of comm like DistributedQueue::new. The queue doesnt do
+    /// anything until the driver runs
+    pub fn new<M: Communicator>(comm: &M) -> Result<(Self, Driver<T, MpiTransport<T>>), QueueError> {
+        Self::with_transport(MpiTransport::with_communicator(comm.duplicate()))
+    }
+
+    /// Queue over any transport, eg the in memory one for ranks running as threads
+    pub fn with_transport<C: Transport<T>>(transport: C) -> Result<(Self, Driver<T, C>), QueueError> {
+        let process = join(transport, None)?;
+        let (requests, incoming) = mpsc::unbounded_channel();
+        let queue = Self { requests, rank: process.index, size: process.num_procs };
+        Ok((queue, Driver { process, requests: incoming, waiting: HashMap::new(), parked: Vec::new() }))
+    }
+
+    pub fn rank(&self) -> Rank {
+        self.rank
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub(crate) fn submit(&self, request: Request<T>) -> Result<(), QueueError> {
+        self.requests.send(request).map_err(|_| QueueError::Stopped)
+    }
+
+    async fn request<R>(&self, request: impl FnOnce(Reply<R>) -> Request<T>) -> Result<R, QueueError> {
+        let (reply, result) = oneshot::channel();
+        self.submit(request(reply))?;
+        result.await.map_err(|_| QueueError::Stopped)?
+    }
+
+    /// Id of the queue called name, see DistributedQueue::open
+    pub async fn open(&self, name: &str) -> Result<QueueId, QueueError> {
+        let name = name.to_string();
+        self.request(|reply| Request::Open { name, reply }).await
+    }
+
+    /// Resolves once every rank holds value
+    pub async fn enqueue(&self, value: T) -> Result<(), QueueError> {
+        self.enqueue_to(DEFAULT_QUEUE, value).await
+    }
+
+    /// Takes the item at the head of the queue, None if it was empty
+    pub async fn dequeue(&self) -> Result<Option<T>, QueueError> {
+        self.dequeue_from(DEFAULT_QUEUE).await
+    }
+
+    /// dequeue that waits for an item instead of returning None, see DistributedQueue::take
+    pub async fn take(&self, timeout: Option<Duration>) -> Result<Option<T>, QueueError> {
+        self.take_from(DEFAULT_QUEUE, timeout).await
+    }
+
+    pub async fn take_from(&self, queue: QueueId, timeout: Option<Duration>) -> Result<Option<T>, QueueError> {
+        self.request(|reply| Request::Take { queue, timeout, reply }).await
+    }
+
+    pub async fn enqueue_to(&self, queue: QueueId, value: T) -> Result<(), QueueError> {
+        let call = QueueCall::Enqueue(value);
+        self.request(|reply| Request::Invoke { queue, call, reply }).await.map(enqueued)
+    }
+
+    pub async fn dequeue_from(&self, queue: QueueId) -> Result<Option<T>, QueueError> {
+        self.request(|reply| Request::Invoke { queue, call: QueueCall::Dequeue, reply }).await.map(dequeued)
+    }
+
+    /// Enqueues all of values in one protocol round, see DistributedQueue::enqueue_many
+    pub async fn enqueue_many(&self, values: &[T]) -> Result<(), QueueError> {
+        self.enqueue_many_to(DEFAULT_QUEUE, values).await
+    }
+
+    /// Takes up to k items in one protocol round, see DistributedQueue::dequeue_up_to
+    pub async fn dequeue_up_to(&self, k: usize) -> Result<Vec<T>, QueueError> {
+        self.dequeue_up_to_from(DEFAULT_QUEUE, k).await
+    }
+
+    pub async fn enqueue_many_to(&self, queue: QueueId, values: &[T]) -> Result<(), QueueError> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        let call = QueueCall::EnqueueMany(values.to_vec());
+        self.request(|reply| Request::Invoke { queue, call, reply }).await.map(enqueued)
+    }
+
+    pub async fn dequeue_up_to_from(&self, queue: QueueId, k: usize) -> Result<Vec<T>, QueueError> {
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+        let call = QueueCall::DequeueUpTo(k);
+        self.request(|reply| Request::Invoke { queue, call, reply }).await.map(dequeued_many)
+    }
+
+    /// Items that dequeues got after whoever awaited them stopped waiting, eg a timeout or
+    /// select! that dropped the future. See DistributedQueue::drain_cancelled
+    pub async fn drain_cancelled(&self) -> Result<Vec<T>, QueueError> {
+        self.request(|reply| Request::DrainCancelled { reply }).await
+    }
+
+    /// The items of the queue as a Stream, each one taken like with take
+    pub fn stream(&self) -> QueueStream<T> {
+        self.stream_from(DEFAULT_QUEUE)
+    }
+
+    pub fn stream_from(&self, queue: QueueId) -> QueueStream<T> {
+        QueueStream::new(self.clone(), queue)
+    }
+
+    /// A Sink that enqueues what goes into it with up to limit enqueues in flight
+    pub fn sink(&self, limit: usize) -> QueueSink<T> {
+        self.sink_to(DEFAULT_QUEUE, limit)
+    }
+
+    pub fn sink_to(&self, queue: QueueId, limit: usize) -> QueueSink<T> {
+        QueueSink::new(self.clone(), queue, limit)
+    }
+}
+
+impl<T: Payload, C: Transport<T>> Driver<T, C> {
+    /// Runs until every handle is dropped and the other ranks are done with us, which is the
+    /// async shutdown. A transport error ends it and fails every op still in flight
+    pub async fn run(mut self) -> Result<(), QueueError> {
+        let result = self.drive().await;
+        if let Err(e) = &result {
+            for (_, waiter) in self.waiting.drain() {
+                match waiter {
+                    Waiter::Op(reply) => {
+                        let _ = reply.send(Err(e.clone()));
+                    }
+                    Waiter::Take(take) => {
+                        let _ = take.reply.send(Err(e.clone()));
+                    }
+                }
+            }
+            for take in self.parked.drain(..) {
+                let _ = take.reply.send(Err(e.clone()));
+            }
+        }
+        result
+    }
+
+    async fn drive(&mut self) -> Result<(), QueueError> {
+        let mut open = true; // some handle is still around
+        let mut idle = IDLE_MIN;
+        loop {
+            let mut busy = false; // handled a request or a message this round
+            while open {
+                match self.requests.try_recv() {
+                    Ok(request) => {
+                        self.start(request)?;
+                        busy = true;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        open = false;
+                        self.process.announce_done()?;
+                    }
+                }
+            }
+
+            while self.process.progress()? {
+                busy = true;
+            }
+            while let Some((id, result)) = self.process.completed.pop_front() {
+                match (self.waiting.remove(&id), result) {
+                    (Some(Waiter::Op(reply)), result) => {
+                        // whoever asked may have stopped waiting, what it dequeued is off every replica by now
+                        if let Err(Ok(result)) = reply.send(Ok(result)) {
+                            self.process.orphan(result);
+                        }
+                    }
+                    (Some(Waiter::Take(take)), QueueResult::Dequeued(Some(value))) => {
+                        if let Err(Ok(value)) = take.reply.send(Ok(Some(value))) {
+                            self.process.orphan(QueueResult::Dequeued(value)); // eg a dropped stream
+                        }
+                    }
+                    (Some(Waiter::Take(take)), _) => self.parked.push(take),
+                    (None, _) => {}
+                }
+            }
+            self.retry_parked(open)?;
+
+            if !open && self.process.finished() {
+                return Ok(());
+            }
+            if busy {
+                idle = IDLE_MIN;
+                task::yield_now().await;
+                continue;
+            }
+            // MPI cant wake us up when something arrives, so poll it less often the longer it
+            // stays quiet. A handle's request still wakes us right away
+            if open {
+                // None means every handle is gone, the next round's try_recv tells
+                if let Ok(Some(request)) = time::timeout(idle, self.requests.recv()).await {
+                    self.start(request)?;
+                    idle = IDLE_MIN;
+                    continue;
+                }
+            } else {
+                time::sleep(idle).await;
+            }
+            idle = (idle * 2).min(IDLE_MAX);
+        }
+    }
+
+    /// Sends parked takes out again once another item arrived, gives up on the ones that ran
+    /// out of time or whose caller is gone
+    fn retry_parked(&mut self, open: bool) -> Result<(), QueueError> {
+        let now = Instant::now();
+        for take in std::mem::take(&mut self.parked) {
+            if take.reply.is_closed() {
+                continue;
+            }
+            if !open {
+                let _ = take.reply.send(Err(QueueError::Stopped)); // cant invoke after DONE
+            } else if self.process.arrived(take.queue) != take.arrived {
+                self.attempt(take)?;
+            } else if matches!(take.deadline, Some(deadline) if now >= deadline) {
+                let _ = take.reply.send(Ok(None));
+            } else {
+                self.parked.push(take);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends out one dequeue for take
+    fn attempt(&mut self, mut take: Take<T>) -> Result<(), QueueError> {
+        take.arrived = self.process.arrived(take.queue);
+        match self.process.invoke(take.queue, QueueCall::Dequeue) {
+            Ok(id) => {
+                self.waiting.insert(id, Waiter::Take(take));
+                Ok(())
+            }
+            Err(e) => {
+                let _ = take.reply.send(Err(e.clone()));
+                Err(e)
+            }
+        }
+    }
+
+    fn start(&mut self, request: Request<T>) -> Result<(), QueueError> {
+        match request {
+            Request::Open { name, reply } => {
+                let _ = reply.send(self.process.open(&name));
+            }
+            Request::Invoke { queue, call, reply } => match self.process.invoke(queue, call) {
+                Ok(id) => {
+                    self.waiting.insert(id, Waiter::Op(reply));
+                }
+                Err(e) => {
+                    let _ = reply.send(Err(e.clone()));
+                    return Err(e);
+                }
+            },
+            Request::Take { queue, timeout, reply } => {
+                let deadline = timeout.map(|timeout| Instant::now() + timeout);
+                self.attempt(Take { queue, arrived: 0, deadline, reply })?;
+            }
+            Request::DrainCancelled { reply } => {
+                let _ = reply.send(Ok(self.process.drain_orphaned()));
+            }
+        }
+        Ok(())
+    }
+}
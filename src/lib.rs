@@ -0,0 +1,20 @@
+//! Distributed FIFO queue over MPI. Every rank keeps a replica ordered by vector timestamps,
+//! see DistributedQueue for embedding it in a program
+
+mod util;
+mod queue;
+mod async_queue;
+mod op_handle;
+mod stream;
+#[doc(hidden)]
+pub mod tools; // the command line tool and what only it needs, not part of the API
+
+pub use queue::DistributedQueue;
+pub use async_queue::{ AsyncQueue, Driver };
+pub use op_handle::OpHandle;
+pub use stream::{ QueueSink, QueueStream };
+pub use util::error::QueueError;
+pub use util::message_structs::{ OpId, QueueId, QueueOpReq, VectorClock, DEFAULT_QUEUE };
+pub use util::payload::Payload;
+pub use util::protocol::{ MessageKind, ProtocolError, PROTOCOL_VERSION };
+pub use util::transport::{ ChannelTransport, MpiTransport, Transport, TransportError };
@@ -0,0 +1,119 @@
+use std::cell::{ RefCell, RefMut };
+use std::time::Duration;
+use crate::util::error::QueueError;
+use crate::util::history::QueueResult;
+use crate::util::message_structs::OpId;
+use crate::util::payload::Payload;
+use crate::util::process::Process;
+use crate::util::transport::Transport;
+
+/// The process of a DistributedQueue, shared with its OpHandles
+pub(crate) struct Shared<T: Payload, C: Transport<T>> {
+    process: RefCell<Process<T, C>>,
+    dropped: RefCell<Vec<OpId>>, // handles dropped while the process was borrowed, cancelled once it is free
+}
+
+impl<T: Payload, C: Transport<T>> Shared<T, C> {
+    pub(crate) fn new(process: Process<T, C>) -> Self {
+        Self { process: RefCell::new(process), dropped: RefCell::new(Vec::new()) }
+    }
+
+    /// Borrows the process, after cancelling whatever handles were dropped while it was busy
+    pub(crate) fn process(&self) -> RefMut<'_, Process<T, C>> {
+        let mut process = self.process.borrow_mut();
+        for id in self.dropped.borrow_mut().drain(..) {
+            process.abandon(id);
+        }
+        process
+    }
+
+    pub(crate) fn process_mut(&mut self) -> &mut Process<T, C> {
+        let process = self.process.get_mut();
+        for id in self.dropped.get_mut().drain(..) {
+            process.abandon(id);
+        }
+        process
+    }
+}
+
+/// An enqueue or dequeue in flight, from DistributedQueue::start_enqueue and friends. Like an
+/// MPI request: the op makes progress whenever the queue handles messages, so the program can
+/// compute in between and test on it now and then, and any number of them can be in flight.
+/// R is what the op gives back, () for enqueues and the item for dequeues. Dropping a handle
+/// cancels it
+pub struct OpHandle<'q, R, T: Payload, C: Transport<T>> {
+    shared: &'q Shared<T, C>,
+    id: OpId,
+    output: fn(QueueResult<T>) -> R, // what the caller gets out of the op's result
+    taken: bool, // result went to wait or cancel, nothing left to cancel on drop
+}
+
+pub(crate) fn enqueued<T: Payload>(result: QueueResult<T>) {
+    match result {
+        QueueResult::Enqueued => (),
+        _ => unreachable!("an enqueue finished as a dequeue"),
+    }
+}
+
+pub(crate) fn dequeued<T: Payload>(result: QueueResult<T>) -> Option<T> {
+    match result {
+        QueueResult::Dequeued(value) => value,
+        _ => unreachable!("a dequeue finished as something else"),
+    }
+}
+
+pub(crate) fn dequeued_many<T: Payload>(result: QueueResult<T>) -> Vec<T> {
+    match result {
+        QueueResult::DequeuedMany(values) => values,
+        _ => unreachable!("a batch dequeue finished as something else"),
+    }
+}
+
+impl<'q, R, T: Payload, C: Transport<T>> OpHandle<'q, R, T, C> {
+    pub(crate) fn new(shared: &'q Shared<T, C>, id: OpId, output: fn(QueueResult<T>) -> R) -> Self {
+        Self { shared, id, output, taken: false }
+    }
+
+    pub fn id(&self) -> OpId {
+        self.id
+    }
+
+    /// Handles whatever arrived and returns whether the op finished, never blocks. Once it
+    /// says true wait returns the result right away
+    pub fn test(&mut self) -> Result<bool, QueueError> {
+        self.shared.process().test(self.id)
+    }
+
+    /// Handles messages until the op finished and returns its result
+    pub fn wait(mut self) -> Result<R, QueueError> {
+        self.taken = true;
+        let result = self.shared.process().wait(self.id)?;
+        Ok((self.output)(result))
+    }
+
+    /// wait for at most timeout, returns whether the op finished. The handle stays usable
+    /// either way
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<bool, QueueError> {
+        self.shared.process().wait_timeout(self.id, timeout)
+    }
+
+    /// Stops waiting for the op and returns its result if it already finished. The other ranks
+    /// know about it already, so it still runs to the end on every rank and the confirmation
+    /// lists stay the same everywhere. A dequeue cancelled in flight still takes its item off
+    /// the queue when it completes, DistributedQueue::drain_cancelled hands it out
+    pub fn cancel(mut self) -> Option<R> {
+        self.taken = true;
+        self.shared.process().cancel(self.id).map(self.output)
+    }
+}
+
+impl<R, T: Payload, C: Transport<T>> Drop for OpHandle<'_, R, T, C> {
+    fn drop(&mut self) {
+        if !self.taken {
+            match self.shared.process.try_borrow_mut() {
+                Ok(mut process) => process.abandon(self.id),
+                Err(_) => self.shared.dropped.borrow_mut().push(self.id),
+            }
+        }
+    }
+}
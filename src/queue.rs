@@ -0,0 +1,351 @@
+use std::thread;
+use std::time::Duration;
+use mpi::Rank;
+use mpi::traits::*;
+use crate::util::error::QueueError;
+use crate::op_handle::{ dequeued, dequeued_many, enqueued, OpHandle, Shared };
+use crate::util::history::QueueCall;
+use crate::util::message_structs::{ QueueId, DEFAULT_QUEUE };
+use crate::util::payload::Payload;
+use crate::util::process::Process;
+use crate::util::transport::{ MpiTransport, Transport };
+
+/// Handle on this rank's replica of the queue. Every rank of the communicator has to make one
+/// and shut it down, enqueue and dequeue are called by whichever rank wants to, on its own.
+/// Besides the default queue it carries any number of named ones, see open
+pub struct DistributedQueue<T: Payload, C: Transport<T> = MpiTransport<T>> {
+    shared: Shared<T, C>, // the process, shared with the OpHandles in flight
+    shut_down: bool,
+}
+
+impl<T: Payload> DistributedQueue<T, MpiTransport<T>> {
+    /// Joins the queue shared by every rank of comm, the world or any split of it. The queue
+    /// talks over its own duplicate of comm, so it doesnt see or take messages the program sends
+    /// on comm itself and queues on disjoint communicators dont see each other. Every rank
+    /// has to call it, it returns once all of them have and agree on the protocol and payload
+    pub fn new<M: Communicator>(comm: &M) -> Result<Self, QueueError> {
+        Self::join(MpiTransport::with_communicator(comm.duplicate()), None)
+    }
+
+    /// new for a program that was configured for procs ranks, fails on every rank if comm
+    /// or any rank's configuration has another size
+    pub fn with_size<M: Communicator>(comm: &M, procs: usize) -> Result<Self, QueueError> {
+        Self::join(MpiTransport::with_communicator(comm.duplicate()), Some(procs))
+    }
+}
+
+impl<T: Payload, C: Transport<T>> DistributedQueue<T, C> {
+    /// Queue over any transport, eg the in memory one for ranks running as threads
+    pub fn with_transport(transport: C) -> Result<Self, QueueError> {
+        Self::join(transport, None)
+    }
+
+    fn join(transport: C, expected: Option<usize>) -> Result<Self, QueueError> {
+        Ok(Self { shared: Shared::new(join(transport, expected)?), shut_down: false })
+    }
+
+    pub fn rank(&self) -> Rank {
+        self.shared.process().index
+    }
+
+    pub fn size(&self) -> usize {
+        self.shared.process().num_procs
+    }
+
+    /// Id of the queue called name, eg "jobs", for enqueue_to and dequeue_from. Ranks only
+    /// have to use the same names, not open them in the same order or at all before the others
+    /// start using them. Fails if two names we opened get the same id
+    pub fn open(&mut self, name: &str) -> Result<QueueId, QueueError> {
+        self.shared.process_mut().open(name)
+    }
+
+    /// Returns once every rank holds value
+    pub fn enqueue(&mut self, value: T) -> Result<(), QueueError> {
+        self.enqueue_to(DEFAULT_QUEUE, value)
+    }
+
+    /// Takes the item at the head of the queue, None if it was empty
+    pub fn dequeue(&mut self) -> Result<Option<T>, QueueError> {
+        self.dequeue_from(DEFAULT_QUEUE)
+    }
+
+    /// enqueue on one of the named queues, it is ordered on its own and never waits for the others
+    pub fn enqueue_to(&mut self, queue: QueueId, value: T) -> Result<(), QueueError> {
+        self.start_enqueue_to(queue, value)?.wait()
+    }
+
+    /// dequeue from one of the named queues
+    pub fn dequeue_from(&mut self, queue: QueueId) -> Result<Option<T>, QueueError> {
+        self.start_dequeue_from(queue)?.wait()
+    }
+
+    /// Enqueues all of values in one protocol round, they end up next to each other in the
+    /// queue in this order and come out in it. Returns once every rank holds them
+    pub fn enqueue_many(&mut self, values: &[T]) -> Result<(), QueueError> {
+        self.enqueue_many_to(DEFAULT_QUEUE, values)
+    }
+
+    /// Takes up to k items off the head of the queue in one protocol round, fewer if there
+    /// arent that many and none if it is empty
+    pub fn dequeue_up_to(&mut self, k: usize) -> Result<Vec<T>, QueueError> {
+        self.dequeue_up_to_from(DEFAULT_QUEUE, k)
+    }
+
+    pub fn enqueue_many_to(&mut self, queue: QueueId, values: &[T]) -> Result<(), QueueError> {
+        if values.is_empty() {
+            return Ok(()); // nothing for the other ranks to hear about
+        }
+        let process = self.shared.process_mut();
+        let op = process.invoke(queue, QueueCall::EnqueueMany(values.to_vec()))?;
+        process.wait(op).map(enqueued)
+    }
+
+    pub fn dequeue_up_to_from(&mut self, queue: QueueId, k: usize) -> Result<Vec<T>, QueueError> {
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+        let process = self.shared.process_mut();
+        let op = process.invoke(queue, QueueCall::DequeueUpTo(k))?;
+        process.wait(op).map(dequeued_many)
+    }
+
+    /// dequeue that waits for an item when the queue is empty instead of returning None, what
+    /// it gets is the head of the queue like with any dequeue. With a timeout it returns None
+    /// after about that long, without one it waits for good, so some rank had better enqueue
+    pub fn take(&mut self, timeout: Option<Duration>) -> Result<Option<T>, QueueError> {
+        self.take_from(DEFAULT_QUEUE, timeout)
+    }
+
+    pub fn take_from(&mut self, queue: QueueId, timeout: Option<Duration>) -> Result<Option<T>, QueueError> {
+        self.shared.process_mut().take(queue, timeout)
+    }
+
+    /// Sends out an enqueue and returns without waiting for the acks, the handle tells when
+    /// it finished
+    pub fn start_enqueue(&self, value: T) -> Result<OpHandle<'_, (), T, C>, QueueError> {
+        self.start_enqueue_to(DEFAULT_QUEUE, value)
+    }
+
+    /// Sends out a dequeue and returns without waiting for its confirmation lists, the handle
+    /// gives the item once it finished
+    pub fn start_dequeue(&self) -> Result<OpHandle<'_, Option<T>, T, C>, QueueError> {
+        self.start_dequeue_from(DEFAULT_QUEUE)
+    }
+
+    pub fn start_enqueue_to(&self, queue: QueueId, value: T) -> Result<OpHandle<'_, (), T, C>, QueueError> {
+        let op = self.shared.process().invoke(queue, QueueCall::Enqueue(value))?;
+        Ok(OpHandle::new(&self.shared, op, enqueued))
+    }
+
+    pub fn start_dequeue_from(&self, queue: QueueId) -> Result<OpHandle<'_, Option<T>, T, C>, QueueError> {
+        let op = self.shared.process().invoke(queue, QueueCall::Dequeue)?;
+        Ok(OpHandle::new(&self.shared, op, dequeued))
+    }
+
+    /// Items that dequeues took off the queue after their handles were cancelled or dropped, in
+    /// the order those finished. They are gone from every replica, so this is the only place
+    /// they turn up
+    pub fn drain_cancelled(&mut self) -> Vec<T> {
+        self.shared.process_mut().drain_orphaned()
+    }
+
+    /// Handles whatever the other ranks sent without invoking anything, returns whether there
+    /// was anything. Ranks that go quiet for a while should call it so the others arent held up
+    pub fn poll(&mut self) -> Result<bool, QueueError> {
+        self.shared.process_mut().progress()
+    }
+
+    /// Tells the other ranks this one is done and keeps answering them until they are too.
+    /// Blocks until every rank has called it
+    pub fn shutdown(mut self) -> Result<(), QueueError> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> Result<(), QueueError> {
+        if !self.shut_down {
+            self.shut_down = true;
+            self.shared.process_mut().shutdown()?;
+        }
+        Ok(())
+    }
+}
+
+/// Process for one rank of a queue, once every rank got this far and agrees on how it runs
+pub(crate) fn join<T: Payload, C: Transport<T>>(transport: C, expected: Option<usize>) -> Result<Process<T, C>, QueueError> {
+    let mut process = Process::initialize(transport);
+    process.verbose = false; // the protocol chatter is for the command line tool
+    process.handshake(expected)?;
+    Ok(process)
+}
+
+impl<T: Payload, C: Transport<T>> Drop for DistributedQueue<T, C> {
+    /// Shuts down if nobody did, the other ranks would wait for us forever otherwise. Not while
+    /// panicking, they are stuck anyway and waiting on them would hide the panic
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            if let Err(e) = self.finish() {
+                eprintln!("rank {} couldnt shut its queue down: {}", self.rank(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use super::*;
+    use crate::util::transport::ChannelTransport;
+
+    type ChannelQueue = DistributedQueue<u32, ChannelTransport<u32>>;
+
+    /// Runs rank on a thread per rank of a ChannelTransport mesh, results in rank order
+    fn on_threads<R: Send + 'static>(num_procs: usize, rank: fn(ChannelQueue) -> R) -> Vec<R> {
+        let threads: Vec<_> = ChannelTransport::mesh(num_procs).into_iter()
+            .map(|transport| thread::spawn(move || rank(DistributedQueue::with_transport(transport).unwrap())))
+            .collect();
+        threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+    }
+
+    /// A communicator of one rank, eg the odd half of split_queues under mpiexec -n 3, has
+    /// nobody to ack or answer its ops
+    #[test]
+    fn queue_of_one_rank() {
+        let transport = ChannelTransport::<u32>::mesh(1).pop().unwrap();
+        let mut queue = DistributedQueue::with_transport(transport).unwrap();
+        queue.enqueue(1).unwrap();
+        queue.enqueue_many(&[2, 3]).unwrap();
+        assert_eq!(queue.dequeue().unwrap(), Some(1));
+        assert_eq!(queue.dequeue_up_to(5).unwrap(), vec![2, 3]);
+        assert_eq!(queue.dequeue().unwrap(), None);
+        assert_eq!(queue.take(Some(Duration::from_millis(10))).unwrap(), None);
+        queue.shutdown().unwrap();
+    }
+
+    /// A dequeue cancelled in flight and one whose handle is dropped still take their items,
+    /// drain_cancelled hands them out once the dequeues finished
+    #[test]
+    fn cancelled_dequeues_keep_their_items() {
+        let cancelled = on_threads(2, |mut queue| {
+            let mut cancelled = Vec::new();
+            if queue.rank() == 0 {
+                queue.enqueue_many(&[1, 2, 3]).unwrap();
+                assert_eq!(queue.start_dequeue().unwrap().cancel(), None); // rank 1 hasnt answered yet
+                drop(queue.start_dequeue().unwrap());
+                while cancelled.len() < 2 {
+                    queue.poll().unwrap();
+                    cancelled.extend(queue.drain_cancelled());
+                }
+                assert_eq!(queue.dequeue().unwrap(), Some(3));
+            }
+            cancelled.sort(); // both were in flight at once, either can get either item
+            queue.shutdown().unwrap();
+            cancelled
+        });
+        assert_eq!(cancelled, vec![vec![1, 2], vec![]]);
+    }
+
+    /// take gives up after its timeout on an empty queue and otherwise waits for the item. Rank 1
+    /// only enqueues once it took rank 0's go from another queue, so rank 0's first take cant
+    /// see anything. Each queue has one rank taking from it
+    #[test]
+    fn take_waits_for_an_item() {
+        let taken = on_threads(2, |mut queue| {
+            let go = queue.open("go").unwrap();
+            let taken = if queue.rank() == 0 {
+                let started = Instant::now();
+                assert_eq!(queue.take(Some(Duration::from_millis(20))).unwrap(), None);
+                assert!(started.elapsed() >= Duration::from_millis(20));
+                queue.enqueue_to(go, 100).unwrap();
+                queue.take(Some(Duration::from_secs(10))).unwrap()
+            } else {
+                assert_eq!(queue.take_from(go, None).unwrap(), Some(100));
+                queue.enqueue(7).unwrap();
+                None
+            };
+            queue.shutdown().unwrap();
+            taken
+        });
+        assert_eq!(taken, vec![Some(7), None]);
+    }
+
+    /// Batches from two ranks at once each stay in one piece in the order given, and batch
+    /// dequeues take what is there once less is left than they ask for
+    #[test]
+    fn batches_stay_together() {
+        let taken = on_threads(2, |mut queue| {
+            let go = queue.open("go").unwrap();
+            let mut taken = Vec::new();
+            if queue.rank() == 0 {
+                queue.enqueue_many(&[1, 2, 3]).unwrap();
+                queue.take_from(go, None).unwrap(); // rank 1's batch is in too
+                taken.push(queue.dequeue_up_to(4).unwrap());
+                taken.push(queue.dequeue_up_to(4).unwrap());
+                taken.push(queue.dequeue_up_to(4).unwrap());
+            } else {
+                queue.enqueue_many(&[10, 20, 30]).unwrap();
+                queue.enqueue_to(go, 0).unwrap();
+            }
+            queue.shutdown().unwrap();
+            taken
+        });
+
+        let sizes: Vec<_> = taken[0].iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![4, 2, 0]);
+        let all = taken[0].concat();
+        assert!(all == [1, 2, 3, 10, 20, 30] || all == [10, 20, 30, 1, 2, 3], "{:?}", all);
+    }
+
+    /// Ops on two named queues are ordered per queue, whatever order they were interleaved in.
+    /// Rank 0 fills both, then each queue has one rank dequeueing from it
+    #[test]
+    fn named_queues_are_ordered_apart() {
+        let taken = on_threads(2, |mut queue| {
+            let (a, b, go) = (queue.open("a").unwrap(), queue.open("b").unwrap(), queue.open("go").unwrap());
+            assert_ne!(a, b);
+            let from = if queue.rank() == 0 {
+                for (queue_id, value) in [(a, 1), (b, 10), (b, 20), (a, 2), (b, 30)] {
+                    queue.enqueue_to(queue_id, value).unwrap();
+                }
+                queue.enqueue_to(go, 0).unwrap();
+                b
+            } else {
+                queue.take_from(go, None).unwrap();
+                a
+            };
+            let mut taken = Vec::new();
+            while let Some(value) = queue.dequeue_from(from).unwrap() {
+                taken.push(value);
+            }
+            queue.shutdown().unwrap();
+            taken
+        });
+        assert_eq!(taken, vec![vec![10, 20, 30], vec![1, 2]]);
+    }
+
+    /// Two enqueues of one rank in flight at once both finish, every replica ends up with both
+    /// in the order they were started, and they come out in that order
+    #[test]
+    fn overlapping_enqueues() {
+        let replicas = on_threads(3, |mut queue| {
+            if queue.rank() == 0 {
+                let first = queue.start_enqueue(1).unwrap();
+                let second = queue.start_enqueue(2).unwrap();
+                second.wait().unwrap();
+                first.wait().unwrap();
+            }
+            while queue.shared.process().arrived(DEFAULT_QUEUE) < 2 {
+                queue.poll().unwrap();
+            }
+            let replica: Vec<u32> = queue.shared.process().queues[&DEFAULT_QUEUE].local_queue.iter()
+                .map(|(_, value, _)| *value)
+                .collect();
+            if queue.rank() == 0 {
+                assert_eq!(queue.dequeue().unwrap(), Some(1));
+                assert_eq!(queue.dequeue().unwrap(), Some(2));
+            }
+            queue.shutdown().unwrap();
+            replica
+        });
+        assert_eq!(replicas, vec![vec![1, 2]; 3]);
+    }
+}
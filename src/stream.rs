@@ -0,0 +1,237 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ Context, Poll };
+use futures::stream::FuturesUnordered;
+use futures::{ ready, Sink, Stream, StreamExt };
+use tokio::sync::oneshot;
+use crate::async_queue::{ AsyncQueue, Request };
+use crate::util::error::QueueError;
+use crate::util::history::{ QueueCall, QueueResult };
+use crate::util::message_structs::QueueId;
+use crate::util::payload::Payload;
+
+/// Items taken off a queue one after the other, from AsyncQueue::stream. A take only goes out
+/// when the stream is polled, and it waits for an item, so the stream ends only when the
+/// driver stops or fails. It holds a handle, the driver keeps running until it is dropped.
+/// If a take is in flight then, the item it gets turns up in AsyncQueue::drain_cancelled
+pub struct QueueStream<T: Payload> {
+    queue: AsyncQueue<T>,
+    id: QueueId,
+    taking: Option<oneshot::Receiver<Result<Option<T>, QueueError>>>, // the take in flight
+    ended: bool,
+}
+
+impl<T: Payload> QueueStream<T> {
+    pub(crate) fn new(queue: AsyncQueue<T>, id: QueueId) -> Self {
+        Self { queue, id, taking: None, ended: false }
+    }
+}
+
+impl<T: Payload> Stream for QueueStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        while !this.ended {
+            let taking = match &mut this.taking {
+                Some(taking) => taking,
+                None => {
+                    let (reply, taking) = oneshot::channel();
+                    if this.queue.submit(Request::Take { queue: this.id, timeout: None, reply }).is_err() {
+                        this.ended = true;
+                        break;
+                    }
+                    this.taking.insert(taking)
+                }
+            };
+            let taken = ready!(Pin::new(taking).poll(cx));
+            this.taking = None;
+            match taken {
+                Ok(Ok(Some(value))) => return Poll::Ready(Some(value)),
+                Ok(Ok(None)) => {} // takes without a timeout dont come back empty, but try again if one does
+                Ok(Err(_)) | Err(_) => this.ended = true,
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+/// Enqueues whatever is sent into it, from AsyncQueue::sink. Up to limit enqueues are in
+/// flight at once, poll_ready waits until one of them finished before it takes the next item,
+/// and flushing waits for all of them. Enqueues in flight together are concurrent and can end
+/// up in the queue in any order, with a limit of 1 the items keep the order they were sent in
+pub struct QueueSink<T: Payload> {
+    queue: AsyncQueue<T>,
+    id: QueueId,
+    limit: usize,
+    in_flight: FuturesUnordered<oneshot::Receiver<Result<QueueResult<T>, QueueError>>>,
+}
+
+impl<T: Payload> QueueSink<T> {
+    pub(crate) fn new(queue: AsyncQueue<T>, id: QueueId, limit: usize) -> Self {
+        Self { queue, id, limit: limit.max(1), in_flight: FuturesUnordered::new() }
+    }
+
+    /// Collects finished enqueues until no more than max are left in flight, the first one
+    /// that failed fails the sink
+    fn poll_in_flight(&mut self, cx: &mut Context<'_>, max: usize) -> Poll<Result<(), QueueError>> {
+        while self.in_flight.len() > max {
+            match ready!(self.in_flight.poll_next_unpin(cx)) {
+                Some(Ok(Ok(_))) => {}
+                Some(Ok(Err(e))) => return Poll::Ready(Err(e)),
+                Some(Err(_)) => return Poll::Ready(Err(QueueError::Stopped)),
+                None => break,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: Payload> Sink<T> for QueueSink<T> {
+    type Error = QueueError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), QueueError>> {
+        let this = self.get_mut();
+        this.poll_in_flight(cx, this.limit - 1)
+    }
+
+    fn start_send(self: Pin<&mut Self>, value: T) -> Result<(), QueueError> {
+        let this = self.get_mut();
+        let (reply, enqueued) = oneshot::channel();
+        this.queue.submit(Request::Invoke { queue: this.id, call: QueueCall::Enqueue(value), reply })?;
+        this.in_flight.push(enqueued);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), QueueError>> {
+        self.get_mut().poll_in_flight(cx, 0)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), QueueError>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use futures::task::noop_waker;
+    use futures::SinkExt;
+    use tokio::task::{ self, LocalSet };
+    use super::*;
+    use crate::util::transport::ChannelTransport;
+
+    /// Runs rank on a thread per rank of a ChannelTransport mesh, each with its own runtime and
+    /// driver. Results in rank order
+    fn on_threads<R, F>(num_procs: usize, rank: fn(AsyncQueue<u32>) -> F) -> Vec<R>
+    where
+        R: Send + 'static,
+        F: Future<Output = R> + 'static,
+    {
+        let threads: Vec<_> = ChannelTransport::mesh(num_procs).into_iter()
+            .map(|transport| thread::spawn(move || {
+                let (queue, driver) = AsyncQueue::with_transport(transport).unwrap();
+                let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+                LocalSet::new().block_on(&runtime, async move {
+                    let driver = task::spawn_local(driver.run());
+                    let result = rank(queue).await; // drops the last handle, which stops the driver
+                    driver.await.unwrap().unwrap();
+                    result
+                })
+            }))
+            .collect();
+        threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+    }
+
+    /// The sink holds back once limit enqueues are in flight and lets go when they finished,
+    /// the stream only takes while it is polled so a take after it still gets the last item.
+    /// Rank 1 starts taking once rank 0 says go on another queue
+    #[test]
+    fn backpressure() {
+        let taken = on_threads(2, |queue| async move {
+            let go = queue.open("go").await.unwrap();
+            if queue.rank() == 0 {
+                let mut sink = queue.sink(2);
+                let waker = noop_waker();
+                let mut cx = Context::from_waker(&waker);
+                for value in [1, 2] {
+                    assert!(Pin::new(&mut sink).poll_ready(&mut cx).is_ready());
+                    Pin::new(&mut sink).start_send(value).unwrap();
+                }
+                assert!(Pin::new(&mut sink).poll_ready(&mut cx).is_pending()); // the driver didnt get to run yet
+                sink.flush().await.unwrap();
+                assert!(Pin::new(&mut sink).poll_ready(&mut cx).is_ready());
+                sink.send(3).await.unwrap();
+                sink.send(4).await.unwrap();
+                queue.enqueue_to(go, 0).await.unwrap();
+                Vec::new()
+            } else {
+                queue.take_from(go, None).await.unwrap();
+                let mut stream = queue.stream();
+                let mut taken: Vec<u32> = (&mut stream).take(3).collect().await;
+                drop(stream);
+                taken.push(queue.take(None).await.unwrap().unwrap());
+                taken
+            }
+        });
+        let mut first = taken[1][..2].to_vec();
+        first.sort(); // 1 and 2 were in flight together
+        assert_eq!(first, vec![1, 2]);
+        assert_eq!(taken[1][2..], [3, 4]);
+    }
+
+    /// What a timeout or select! does to the future that loses, the first poll sends the request
+    fn poll_once_and_drop<F: Future>(future: F) {
+        let waker = noop_waker();
+        assert!(Box::pin(future).as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+    }
+
+    /// A dequeue whose future is dropped after it went out still takes its item, and
+    /// drain_cancelled hands it out instead of it being lost with the reply
+    #[test]
+    fn dropped_dequeues_keep_their_items() {
+        let cancelled = on_threads(2, |queue| async move {
+            let mut cancelled = Vec::new();
+            if queue.rank() == 0 {
+                queue.enqueue_many(&[1, 2]).await.unwrap();
+                poll_once_and_drop(queue.dequeue());
+                poll_once_and_drop(queue.dequeue_up_to(1));
+                while cancelled.len() < 2 {
+                    cancelled.extend(queue.drain_cancelled().await.unwrap());
+                    task::yield_now().await;
+                }
+                assert_eq!(queue.dequeue().await.unwrap(), None);
+            }
+            cancelled.sort(); // both were in flight at once, either can get either item
+            cancelled
+        });
+        assert_eq!(cancelled[0], vec![1, 2]);
+    }
+
+    /// A stream dropped with a take in flight doesnt lose the item that take gets. Rank 1
+    /// says on another queue once the item is there, so the take doesnt park
+    #[test]
+    fn dropped_streams_keep_their_items() {
+        let cancelled = on_threads(2, |queue| async move {
+            let go = queue.open("go").await.unwrap();
+            let mut cancelled = Vec::new();
+            if queue.rank() == 0 {
+                queue.take_from(go, None).await.unwrap();
+                let mut stream = queue.stream();
+                let waker = noop_waker();
+                assert!(stream.poll_next_unpin(&mut Context::from_waker(&waker)).is_pending());
+                drop(stream);
+                while cancelled.is_empty() {
+                    cancelled.extend(queue.drain_cancelled().await.unwrap());
+                    task::yield_now().await;
+                }
+                assert_eq!(queue.dequeue().await.unwrap(), None);
+            } else {
+                queue.enqueue(7).await.unwrap();
+                queue.enqueue_to(go, 0).await.unwrap();
+            }
+            cancelled
+        });
+        assert_eq!(cancelled[0], vec![7]);
+    }
+}
@@ -0,0 +1,557 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use mpi::Rank;
+use mpi::traits::*;
+use crate::tools::explorer::explore;
+use crate::util::history::QueueCall;
+use crate::tools::history::{ random_workload, History };
+use crate::tools::linearizability::{ check, CheckResult };
+use crate::util::message_structs::DEFAULT_QUEUE;
+use crate::util::payload::Payload;
+use crate::tools::faults::{ FaultPolicy, FaultyTransport };
+use crate::util::process::{ DuplicatePolicy, Process };
+use crate::tools::scenario::Scenario;
+use crate::tools::simulator::{ SimAction, Simulator };
+use crate::util::trace::{ trace_file, TraceRecorder };
+use crate::tools::traces::{ merge_trace_files, read_trace, merge_traces, trace_history, trace_queues };
+use crate::util::transport::{ ChannelTransport, MpiTransport, Transport };
+
+const DEFAULT_SCENARIO: &str = include_str!("../../scenarios/fixed_linearization.scn");
+
+#[derive(Parser)]
+#[command(about = "Distributed FIFO queue over vector clocks")]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    #[command(flatten)]
+    options: Options,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Random workload of enqueues and dequeues, some of them batches, checked for linearizability afterwards
+    Run,
+    /// Runs a scenario file in lockstep on every rank, the built in one if no file is given
+    Scenario { file: Option<PathBuf> },
+    /// Times a random workload
+    Bench,
+    /// Runs a small random workload through every delivery order in the simulator, checking
+    /// linearizability and that the ranks agree on the queue at the end
+    Explore {
+        /// Stop after this many violations
+        #[arg(long, default_value_t = 1)]
+        max_violations: usize,
+    },
+    /// Checks a recorded trace for linearizability, per rank files are merged first
+    Check {
+        #[arg(required = true)]
+        traces: Vec<PathBuf>,
+    },
+    /// Merges per rank trace files into one causally ordered trace
+    Merge {
+        #[arg(required = true)]
+        traces: Vec<PathBuf>,
+    },
+}
+
+#[derive(Args)]
+struct Options {
+    /// How ranks talk to each other. mpi needs mpirun, memory runs ranks as threads and sim
+    /// runs them in a seeded simulator
+    #[arg(long, global = true, value_enum, default_value_t = TransportKind::Mpi)]
+    transport: TransportKind,
+
+    /// Seed for the workload and for the simulator's scheduling
+    #[arg(long, global = true, default_value_t = 0)]
+    seed: u64,
+
+    /// Number of operations in the workload, over all ranks
+    #[arg(long, global = true, default_value_t = 20)]
+    ops: usize,
+
+    /// Ranks for the memory and sim transports (3 if not given). With mpi the world size
+    /// every rank expects, the run fails if mpiexec started another number
+    #[arg(long, global = true)]
+    procs: Option<usize>,
+
+    /// Operations a rank may have in flight before it waits for the oldest one
+    #[arg(long, global = true, default_value_t = 1)]
+    pipeline: usize,
+
+    /// Chance a message is held up before it is sent, memory and mpi only
+    #[arg(long, global = true, default_value_t = 0.0)]
+    delay: f64,
+
+    /// Longest hold up for --delay, in milliseconds
+    #[arg(long, global = true, default_value_t = 10)]
+    max_delay_ms: u64,
+
+    /// Chance a message overtakes an earlier one to the same rank
+    #[arg(long, global = true, default_value_t = 0.0)]
+    reorder: f64,
+
+    /// Chance a message is delivered twice
+    #[arg(long, global = true, default_value_t = 0.0)]
+    duplicate: f64,
+
+    /// Directory for traces (rank-<rank>.jsonl and merged.jsonl), merge writes merged.jsonl here
+    #[arg(long, global = true)]
+    out: Option<PathBuf>,
+}
+
+impl Options {
+    fn procs(&self) -> usize {
+        self.procs.unwrap_or(3)
+    }
+
+    fn faults(&self) -> FaultPolicy {
+        FaultPolicy {
+            delay: self.delay,
+            max_delay: Duration::from_millis(self.max_delay_ms),
+            reorder: self.reorder,
+            duplicate: self.duplicate,
+            seed: self.seed,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum TransportKind {
+    Mpi,
+    Memory,
+    Sim,
+}
+
+/// Parses the arguments and runs the subcommand, returns the exit code
+pub fn main() -> i32 {
+    let cli = Cli::parse();
+    let options = &cli.options;
+
+    match cli.command {
+        Command::Run => run(options),
+        Command::Scenario { file } => scenario(options, file.as_deref()),
+        Command::Bench => bench(options),
+        Command::Explore { max_violations } => explore_workload(options, max_violations),
+        Command::Check { traces } => check_traces(&traces),
+        Command::Merge { traces } => {
+            let out = options.out.clone().unwrap_or_default().join("merged.jsonl");
+            match merge_trace_files(&out, &traces) {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("merge: {}", e);
+                    1
+                }
+            }
+        }
+    }
+}
+
+type Workload = Vec<(Rank, QueueCall<u16>)>;
+
+type JobError = Box<dyn Error + Send + Sync>;
+
+/// What every rank runs, generic over the transport so one job serves mpi and memory
+trait Job: Clone + Send + 'static {
+    fn run<C: Transport<u16>>(&self, process: &mut Process<u16, C>) -> Result<(), JobError>;
+}
+
+#[derive(Clone)]
+struct WorkloadJob {
+    workload: Workload,
+    pipeline: usize, // ops in flight per rank
+}
+
+impl WorkloadJob {
+    fn new(options: &Options, procs: usize) -> Self {
+        Self {
+            workload: random_workload(options.seed, procs, options.ops),
+            pipeline: options.pipeline.max(1),
+        }
+    }
+}
+
+impl Job for WorkloadJob {
+    /// Each rank invokes its own share of the workload on its own, keeping up to pipeline ops
+    /// in flight
+    fn run<C: Transport<u16>>(&self, process: &mut Process<u16, C>) -> Result<(), JobError> {
+        let mut in_flight = VecDeque::new();
+        for (invoker, call) in &self.workload {
+            if *invoker == process.index {
+                if in_flight.len() == self.pipeline {
+                    process.wait(in_flight.pop_front().unwrap())?;
+                }
+                in_flight.push_back(process.invoke(DEFAULT_QUEUE, call.clone())?);
+            }
+        }
+        for id in in_flight {
+            process.wait(id)?;
+        }
+        process.shutdown()?;
+        Ok(())
+    }
+}
+
+impl Job for Scenario<u16> {
+    /// A bad scenario fails the same way on every rank, so nobody is left waiting
+    fn run<C: Transport<u16>>(&self, process: &mut Process<u16, C>) -> Result<(), JobError> {
+        Ok(Scenario::run(self, process)?)
+    }
+}
+
+/// Process on top of the fault layer, which passes everything through untouched by default
+fn traced_process<C: Transport<u16>>(transport: C, faults: &FaultPolicy, out: Option<&Path>) -> Result<Process<u16, FaultyTransport<u16, C>>, String> {
+    let mut process = Process::initialize(FaultyTransport::new(transport, faults.clone()));
+    if !faults.is_none() {
+        process.on_duplicate = DuplicatePolicy::Report;
+    }
+    if let Some(dir) = out {
+        let recorder = TraceRecorder::to_dir(process.index, dir)
+            .map_err(|e| format!("couldnt create a trace file in {}: {}", dir.display(), e))?;
+        process.trace_to(recorder);
+    }
+    Ok(process)
+}
+
+/// Fails if the trace of process stopped early, checking what is left of it would mislead
+fn trace_written<T: Payload, C: Transport<T>>(process: &Process<T, C>) -> Result<(), String> {
+    match process.trace_error() {
+        Some(e) => Err(format!("rank {} couldnt write its trace: {}", process.index, e)),
+        None => Ok(()),
+    }
+}
+
+struct Ran {
+    elapsed: Duration, // how long this rank took
+    procs: usize,
+    reporter: bool, // rank 0, or the main thread for memory, reports for everyone
+    failed: bool, // the job failed on this rank, or on any thread for memory
+}
+
+/// Checks the ranks agree on how they run before the job sends anything, expected is the world
+/// size from --procs
+fn handshake_and_run<J: Job, C: Transport<u16>>(job: &J, process: &mut Process<u16, C>, expected: Option<usize>) -> Result<(), JobError> {
+    process.handshake(expected)?;
+    job.run(process)?;
+    Ok(trace_written(process)?)
+}
+
+/// Prints what the fault layer did to this rank's sends and how many duplicates it dropped
+fn report_faults<C: Transport<u16>>(process: &Process<u16, FaultyTransport<u16, C>>) {
+    let stats = process.transport.stats();
+    eprintln!("rank {}: delayed {}, reordered {} and duplicated {} sends, dropped {} duplicates",
+              process.index, stats.delayed, stats.reordered, stats.duplicated, process.duplicates.len());
+}
+
+/// Prints why the job failed on rank, returns whether it did
+fn failed(rank: Rank, result: Result<(), JobError>) -> bool {
+    match result {
+        Ok(()) => false,
+        Err(e) => {
+            eprintln!("rank {}: {}", rank, e);
+            true
+        }
+    }
+}
+
+/// Builds the job once the number of ranks is known and runs it on every rank of the chosen
+/// transport
+fn on_every_rank<J: Job>(options: &Options, make_job: impl FnOnce(usize) -> J) -> Result<Ran, String> {
+    let out = options.out.clone();
+    let faults = options.faults();
+    match options.transport {
+        TransportKind::Mpi => {
+            let universe = mpi::initialize().ok_or("couldnt initialize MPI, it already was")?;
+            let world = universe.world();
+            if world.rank() == 0 {
+                print_rectangle(format!("Starting Execution with {} Processes", world.size()));
+            }
+            let job = make_job(world.size() as usize);
+            let mut process = traced_process(MpiTransport::new(&universe), &faults, out.as_deref())?;
+            let start = Instant::now();
+            let failed = failed(world.rank(), handshake_and_run(&job, &mut process, options.procs));
+            let elapsed = start.elapsed();
+            if !faults.is_none() {
+                report_faults(&process);
+            }
+            world.barrier(); // every trace is written before rank 0 reads them
+            Ok(Ran { elapsed, procs: world.size() as usize, reporter: world.rank() == 0, failed })
+        }
+        TransportKind::Memory => {
+            let job = make_job(options.procs());
+            let start = Instant::now();
+            let ranks: Vec<_> = ChannelTransport::<u16>::mesh(options.procs()).into_iter()
+                .map(|transport| {
+                    let job = job.clone();
+                    let out = out.clone();
+                    let faults = faults.clone();
+                    let rank = transport.rank();
+                    thread::spawn(move || {
+                        let mut process = match traced_process(transport, &faults, out.as_deref()) {
+                            Ok(process) => process,
+                            Err(e) => return failed(rank, Err(e.into())), // the others see us hang up
+                        };
+                        let failed = failed(rank, handshake_and_run(&job, &mut process, None));
+                        if !faults.is_none() {
+                            report_faults(&process);
+                        }
+                        failed
+                    })
+                })
+                .collect();
+            let mut any_failed = false;
+            for rank in ranks {
+                any_failed |= rank.join().map_err(|_| "a rank panicked")?;
+            }
+            Ok(Ran { elapsed: start.elapsed(), procs: options.procs(), reporter: true, failed: any_failed })
+        }
+        TransportKind::Sim => unreachable!("the simulator doesnt run lockstep jobs"),
+    }
+}
+
+fn run(options: &Options) -> i32 {
+    if options.transport == TransportKind::Sim {
+        let workload = random_workload(options.seed, options.procs(), options.ops);
+        return match simulate(options, workload) {
+            Ok(history) => report(&history),
+            Err(e) => {
+                eprintln!("{}", e);
+                2
+            }
+        };
+    }
+
+    let ran = match on_every_rank(options, |procs| WorkloadJob::new(options, procs)) {
+        Ok(ran) if ran.failed => return 1,
+        Ok(ran) => ran,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    if !ran.reporter {
+        return 0;
+    }
+    match &options.out {
+        Some(dir) => match merged_history(dir, ran.procs) {
+            Ok(history) => report(&history),
+            Err(e) => {
+                eprintln!("couldnt read traces: {}", e);
+                1
+            }
+        },
+        None => {
+            println!("pass --out to record traces and check the run");
+            0
+        }
+    }
+}
+
+fn explore_workload(options: &Options, max_violations: usize) -> i32 {
+    if options.procs() < 2 || options.procs() > 4 {
+        eprintln!("explore is meant for 2 to 4 ranks");
+        return 2;
+    }
+    let workload = per_rank(options, random_workload(options.seed, options.procs(), options.ops));
+    let exploration = explore(workload.clone(), max_violations);
+
+    println!("{} terminal states, {} steps, {} violations",
+             exploration.terminals, exploration.steps, exploration.violations.len());
+    for violation in &exploration.violations {
+        println!("{}", violation);
+    }
+    if let (Some(dir), Some(violation)) = (&options.out, exploration.violations.first()) {
+        match replay(workload, &violation.schedule, dir) {
+            Ok(()) => println!("traces of the first violation are in {}", dir.display()),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    if exploration.violations.is_empty() { 0 } else { 1 }
+}
+
+/// Runs schedule again with traces going to dir, to look at a violation like any recorded run
+fn replay(workload: Vec<Vec<QueueCall<u16>>>, schedule: &[SimAction], dir: &Path) -> Result<(), String> {
+    let mut simulator = Simulator::new(0, workload);
+    simulator.set_verbose(false);
+    trace_to_dir(&mut simulator, dir)?;
+    simulator.run_schedule(schedule);
+    merge_simulated(&simulator, dir)
+}
+
+fn trace_to_dir(simulator: &mut Simulator<u16>, dir: &Path) -> Result<(), String> {
+    for (rank, process) in simulator.processes.iter_mut().enumerate() {
+        let recorder = TraceRecorder::to_dir(rank as Rank, dir)
+            .map_err(|e| format!("couldnt create a trace file in {}: {}", dir.display(), e))?;
+        process.trace_to(recorder);
+    }
+    Ok(())
+}
+
+/// Merges the traces of a run in the simulator into dir/merged.jsonl
+fn merge_simulated(simulator: &Simulator<u16>, dir: &Path) -> Result<(), String> {
+    for process in &simulator.processes {
+        trace_written(process)?;
+    }
+    let files: Vec<PathBuf> = (0..simulator.processes.len()).map(|rank| trace_file(dir, rank as Rank)).collect();
+    merge_trace_files(&dir.join("merged.jsonl"), &files).map_err(|e| format!("couldnt merge traces: {}", e))
+}
+
+fn per_rank(options: &Options, workload: Workload) -> Vec<Vec<QueueCall<u16>>> {
+    let mut per_rank = vec![Vec::new(); options.procs()];
+    for (invoker, call) in workload {
+        per_rank[invoker as usize].push(call);
+    }
+    per_rank
+}
+
+/// Splits the workload per rank and runs it in the simulator, traces go to --out if given
+fn simulate(options: &Options, workload: Workload) -> Result<History<u16>, String> {
+    let faults = options.faults();
+    let report_duplicates = !faults.is_none();
+    let mut simulator = Simulator::new(options.seed, per_rank(options, workload))
+        .with_faults(faults)
+        .with_pipeline(options.pipeline);
+    if report_duplicates {
+        for process in simulator.processes.iter_mut() {
+            process.on_duplicate = DuplicatePolicy::Report;
+        }
+    }
+    if let Some(dir) = &options.out {
+        trace_to_dir(&mut simulator, dir)?;
+    }
+    simulator.run();
+    if report_duplicates {
+        for process in &simulator.processes {
+            eprintln!("rank {}: dropped {} duplicates", process.index, process.duplicates.len());
+        }
+    }
+    if let Some(dir) = &options.out {
+        merge_simulated(&simulator, dir)?;
+    }
+
+    Ok(simulator.history)
+}
+
+fn merged_history(dir: &Path, num_procs: usize) -> std::io::Result<History<String>> {
+    let files: Vec<PathBuf> = (0..num_procs).map(|rank| trace_file(dir, rank as Rank)).collect();
+    merge_trace_files(&dir.join("merged.jsonl"), &files)?;
+    Ok(trace_history(&read_trace(&dir.join("merged.jsonl"))?, DEFAULT_QUEUE))
+}
+
+fn report<T: Payload + Eq + Hash>(history: &History<T>) -> i32 {
+    match check(history) {
+        CheckResult::Linearizable(order) => {
+            println!("linearizable, {} operations", order.len());
+            0
+        }
+        CheckResult::NotLinearizable(smallest) => {
+            println!("not linearizable, smallest failing history:\n{}", smallest);
+            1
+        }
+    }
+}
+
+fn scenario(options: &Options, file: Option<&Path>) -> i32 {
+    if options.transport == TransportKind::Sim {
+        eprintln!("scenarios run in lockstep, use --transport mpi or memory");
+        return 2;
+    }
+    let text = match file {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => {
+                eprintln!("couldnt read {}: {}", path.display(), e);
+                return 2;
+            }
+        },
+        None => DEFAULT_SCENARIO.to_string(),
+    };
+    let scenario = match Scenario::<u16>::parse(&text) {
+        Ok(scenario) => scenario,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+
+    match on_every_rank(options, |_| scenario) {
+        Ok(ran) => if ran.failed { 1 } else { 0 },
+        Err(e) => {
+            eprintln!("{}", e);
+            2
+        }
+    }
+}
+
+fn bench(options: &Options) -> i32 {
+    let (elapsed, procs) = if options.transport == TransportKind::Sim {
+        let start = Instant::now();
+        if let Err(e) = simulate(options, random_workload(options.seed, options.procs(), options.ops)) {
+            eprintln!("{}", e);
+            return 2;
+        }
+        (start.elapsed(), options.procs())
+    } else {
+        let ran = match on_every_rank(options, |procs| WorkloadJob::new(options, procs)) {
+            Ok(ran) if ran.failed => return 1,
+            Ok(ran) => ran,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 2;
+            }
+        };
+        if !ran.reporter {
+            return 0;
+        }
+        (ran.elapsed, ran.procs)
+    };
+
+    println!(
+        "{} ops on {} ranks over {:?}: {:.1} ms, {:.0} ops/s",
+        options.ops, procs, options.transport,
+        elapsed.as_secs_f64() * 1000.0,
+        options.ops as f64 / elapsed.as_secs_f64()
+    );
+    0
+}
+
+fn check_traces(traces: &[PathBuf]) -> i32 {
+    let events = match traces.iter().map(|path| read_trace(path)).collect::<std::io::Result<Vec<_>>>() {
+        Ok(events) => events,
+        Err(e) => {
+            eprintln!("couldnt read traces: {}", e);
+            return 2;
+        }
+    };
+    let merged = merge_traces(events);
+    let queues = trace_queues(&merged);
+    if queues.len() <= 1 {
+        let queue = queues.first().copied().unwrap_or(DEFAULT_QUEUE);
+        return report(&trace_history(&merged, queue));
+    }
+    let mut code = 0;
+    for queue in queues {
+        println!("queue {}:", queue);
+        code = code.max(report(&trace_history(&merged, queue)));
+    }
+    code
+}
+
+fn print_rectangle(text: String) {
+    let text_width = text.len();
+    let rectangle_width = text_width + 2;
+
+    // Top border
+    println!("┌{}┐", "─".repeat(rectangle_width));
+
+    // Text with side borders
+    println!("┊ {} ┊", text);
+
+    // Bottom border
+    println!("└{}┘", "─".repeat(rectangle_width));
+}
@@ -0,0 +1,250 @@
+use mpi::Rank;
+use crate::util::message_structs::{OpId, QueueOpReq, RankInfo, VectorClock, DEFAULT_QUEUE};
+use crate::util::process::Process;
+use crate::util::payload::Payload;
+use crate::util::error::QueueError;
+use crate::util::protocol::{ MessageKind, ProtocolError };
+use crate::util::transport::Transport;
+
+
+pub struct DequeueFixedLinearization {
+    pub(crate) invoker: Rank, // stores rank of initial invoker
+    pub(crate) message_buffer: MessageKind,
+    pub(crate) deq_ts: VectorClock,
+    pub(crate) deq_op: OpId,
+}
+
+impl DequeueFixedLinearization {
+    pub(crate) fn new() -> Self {
+        DequeueFixedLinearization {
+            invoker: 0,
+            message_buffer: Default::default(),
+            deq_ts: Default::default(),
+            deq_op: Default::default(),
+        }
+    }
+
+    pub(crate) fn deq_invoke<T: Payload, C: Transport<T>>(&mut self, invoking: Rank, process: &mut Process<T, C>) -> Result<(), QueueError> {
+        self.invoker = invoking;
+        if process.index == invoking {
+            let response = process.sync_send_receive(QueueOpReq{
+                message: MessageKind::DeqInvoke,
+                value: T::default(),
+                sender: invoking,
+                receiver: invoking,
+                op: OpId::default(),
+                timestamp: process.clock(DEFAULT_QUEUE),
+                batch: Vec::new(),
+                up_to: 0,
+                info: RankInfo::default(),
+            })?;
+            self.message_buffer = response.message.next_or(self.message_buffer);
+            self.deq_op = response.op;
+            self.deq_ts = response.ts;
+        }else{
+            process.sync_send_receive(QueueOpReq{
+                message: MessageKind::DeqInvoke,
+                value: T::default(),
+                sender: invoking,
+                receiver: invoking,
+                op: OpId::default(),
+                timestamp: process.clock(DEFAULT_QUEUE),
+                batch: Vec::new(),
+                up_to: 0,
+                info: RankInfo::default(),
+            })?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn deq_req<T: Payload, C: Transport<T>>(&mut self, receiver: Rank, process: &mut Process<T, C>) -> Result<(), QueueError> {
+        if process.index == receiver {
+            let response = process.sync_send_receive(QueueOpReq{
+                message: MessageKind::DeqReq,
+                value: T::default(),
+                sender: self.invoker,
+                receiver,
+                op: self.deq_op,
+                timestamp: self.deq_ts.clone(),
+                batch: Vec::new(),
+                up_to: 0,
+                info: RankInfo::default(),
+            })?;
+            self.message_buffer = response.message.next_or(self.message_buffer);
+            self.deq_op = response.op;
+            self.deq_ts = response.ts;
+        }else {
+            process.sync_send_receive(QueueOpReq{
+                message: MessageKind::DeqReq,
+                value: T::default(),
+                sender: self.invoker,
+                receiver,
+                op: self.deq_op,
+                timestamp: self.deq_ts.clone(),
+                batch: Vec::new(),
+                up_to: 0,
+                info: RankInfo::default(),
+            })?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn safe_unsafe<T: Payload, C: Transport<T>>(&mut self, sender: Rank, receiver: Rank, process: &mut Process<T, C>) -> Result<(), QueueError> {
+        if process.index == receiver {
+            let response = process.sync_send_receive(QueueOpReq{
+                message: self.message_buffer,
+                value: T::default(),
+                sender,
+                receiver,
+                op: self.deq_op,
+                timestamp: self.deq_ts.clone(),
+                batch: Vec::new(),
+                up_to: 0,
+                info: RankInfo::default(),
+            })?;
+            self.message_buffer = response.message.next_or(self.message_buffer);
+        } else {
+            process.sync_send_receive(QueueOpReq{
+                message: self.message_buffer,
+                value: T::default(),
+                sender,
+                receiver,
+                op: self.deq_op,
+                timestamp: self.deq_ts.clone(),
+                batch: Vec::new(),
+                up_to: 0,
+                info: RankInfo::default(),
+            })?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn safe_unsafe_all<T: Payload, C: Transport<T>>(&mut self, sender: Rank, process: &mut Process<T, C>) -> Result<(), QueueError> {
+        for i in 0..process.num_procs {
+            self.safe_unsafe(sender, i as Rank, process)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct EnqueueFixedLinearization<T: Payload> {
+    pub(crate) invoker: Rank, // stores rank of initial invoker
+    pub(crate) message_buffer: MessageKind,
+    pub(crate) enq_ts: VectorClock,
+    pub(crate) enq_op: OpId,
+    value: T
+}
+
+impl<T: Payload> EnqueueFixedLinearization<T> {
+    pub(crate) fn new() -> Self {
+        EnqueueFixedLinearization {
+            invoker: Default::default(),
+            message_buffer: Default::default(),
+            enq_ts: Default::default(),
+            enq_op: Default::default(),
+            value: Default::default()
+        }
+    }
+
+    pub(crate) fn enq_invoke<C: Transport<T>>(&mut self, invoking: Rank, value: T, process: &mut Process<T, C>) -> Result<(), QueueError> {
+        self.invoker = invoking;
+        if process.index == invoking {
+            let response = process.sync_send_receive(QueueOpReq{
+                message: MessageKind::EnqInvoke,
+                value,
+                sender: invoking,
+                receiver: invoking,
+                op: OpId::default(),
+                timestamp: process.clock(DEFAULT_QUEUE),
+                batch: Vec::new(),
+                up_to: 0,
+                info: RankInfo::default(),
+            })?;
+            self.message_buffer = response.message.next_or(self.message_buffer);
+            self.value = response.value.ok_or(ProtocolError::MissingValue(MessageKind::EnqReq))?;
+            self.enq_op = response.op;
+            self.enq_ts = response.ts;
+        } else {
+            process.sync_send_receive(QueueOpReq{
+                message: MessageKind::EnqInvoke,
+                value,
+                sender: invoking,
+                receiver: invoking,
+                op: OpId::default(),
+                timestamp: process.clock(DEFAULT_QUEUE),
+                batch: Vec::new(),
+                up_to: 0,
+                info: RankInfo::default(),
+            })?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn enq_req<C: Transport<T>>(&mut self, receiver: Rank, process: &mut Process<T, C>) -> Result<(), QueueError> {
+        if process.index == receiver {
+            let response = process.sync_send_receive(QueueOpReq{
+                message: MessageKind::EnqReq,
+                value: self.value.clone(),
+                sender: self.invoker,
+                receiver,
+                op: self.enq_op,
+                timestamp: self.enq_ts.clone(),
+                batch: Vec::new(),
+                up_to: 0,
+                info: RankInfo::default(),
+            })?;
+            self.value = response.value.ok_or(ProtocolError::MissingValue(MessageKind::EnqAck))?;
+            self.message_buffer = response.message.next_or(self.message_buffer);
+            self.enq_op = response.op;
+            self.enq_ts = response.ts;
+        } else {
+            process.sync_send_receive(QueueOpReq{
+                message: MessageKind::EnqReq,
+                value: self.value.clone(),
+                sender: self.invoker,
+                receiver,
+                op: self.enq_op,
+                timestamp: self.enq_ts.clone(),
+                batch: Vec::new(),
+                up_to: 0,
+                info: RankInfo::default(),
+            })?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn enq_ack<C: Transport<T>>(&mut self, sender: Rank, process: &mut Process<T, C>) -> Result<(), QueueError> {
+        if process.index == self.invoker {
+            let response = process.sync_send_receive(QueueOpReq{
+                message: MessageKind::EnqAck,
+                value: T::default(),
+                sender,
+                receiver: self.invoker,
+                op: self.enq_op,
+                timestamp: self.enq_ts.clone(),
+                batch: Vec::new(),
+                up_to: 0,
+                info: RankInfo::default(),
+            })?;
+            self.message_buffer = response.message.next_or(self.message_buffer);
+        } else {
+            process.sync_send_receive(QueueOpReq{
+                message: MessageKind::EnqAck,
+                value: T::default(),
+                sender,
+                receiver: self.invoker,
+                op: self.enq_op,
+                timestamp: self.enq_ts.clone(),
+                batch: Vec::new(),
+                up_to: 0,
+                info: RankInfo::default(),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+
+
+
+
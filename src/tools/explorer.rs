@@ -0,0 +1,202 @@
+use std::fmt;
+use mpi::Rank;
+use crate::util::history::QueueCall;
+use crate::tools::history::History;
+use crate::util::message_structs::DEFAULT_QUEUE;
+use crate::tools::linearizability::{ check, CheckResult };
+use crate::tools::simulator::{ SimAction, Simulator };
+
+/// What went wrong in one terminal state
+#[derive(Debug, Clone)]
+pub enum ViolationKind {
+    NotLinearizable(History<u16>), // smallest failing history
+    Disagreement(Vec<Vec<u16>>), // each rank's local queue, values only
+    Stuck(Vec<usize>), // ops that never got a response even though nothing is left to deliver
+}
+
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub schedule: Vec<SimAction>, // replay with Simulator::run_schedule on the same workload
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Exploration {
+    pub terminals: usize, // terminal states checked
+    pub steps: usize, // actions executed over the whole search
+    pub violations: Vec<Violation>,
+}
+
+/// Entry of a sleep set: an action already explored from an earlier state and whether it
+/// produced a history event there
+#[derive(Clone, Copy)]
+struct Slept {
+    action: SimAction,
+    visible: bool,
+}
+
+/// Depth first search over every delivery order the workload allows, with sleep sets so
+/// interleavings that only differ in the order of independent actions are run once.
+/// Two actions are independent when they happen at different ranks (channels are per pair, so
+/// one only appends to channels the other doesnt pop) and they dont both add to the history,
+/// since the checker cares about the real time order of invokes and responses.
+/// Stops once max_violations have been found
+pub(crate) fn explore(workload: Vec<Vec<QueueCall<u16>>>, max_violations: usize) -> Exploration {
+    let mut simulator = Simulator::new(0, workload);
+    simulator.set_verbose(false);
+
+    let mut exploration = Exploration::default();
+    search(&simulator, Vec::new(), max_violations, &mut exploration);
+    exploration
+}
+
+fn search(state: &Simulator<u16>, sleep: Vec<Slept>, max_violations: usize, exploration: &mut Exploration) {
+    if exploration.violations.len() >= max_violations {
+        return;
+    }
+
+    let enabled = state.enabled();
+    if enabled.is_empty() {
+        exploration.terminals += 1;
+        if let Some(kind) = check_terminal(state) {
+            exploration.violations.push(Violation { kind, schedule: state.schedule.clone() });
+        }
+        return;
+    }
+
+    let mut done: Vec<Slept> = Vec::new();
+    for action in enabled {
+        if sleep.iter().any(|slept| slept.action == action) {
+            continue; // some earlier branch covers every run starting with this action
+        }
+
+        let mut next = state.clone();
+        let events = next.history.events.len();
+        next.step(action);
+        exploration.steps += 1;
+        let taken = Slept { action, visible: next.history.events.len() != events };
+
+        let next_sleep = sleep.iter().chain(done.iter())
+            .filter(|slept| independent(**slept, taken))
+            .copied()
+            .collect();
+        search(&next, next_sleep, max_violations, exploration);
+        done.push(taken);
+    }
+}
+
+fn rank_of(action: SimAction) -> Rank {
+    match action {
+        SimAction::Invoke(rank) => rank,
+        SimAction::Deliver { to, .. } => to,
+    }
+}
+
+fn independent(a: Slept, b: Slept) -> bool {
+    rank_of(a.action) != rank_of(b.action) && !(a.visible && b.visible)
+}
+
+fn check_terminal(state: &Simulator<u16>) -> Option<ViolationKind> {
+    let pending = state.history.pending();
+    if !pending.is_empty() {
+        return Some(ViolationKind::Stuck(pending));
+    }
+
+    let queues: Vec<Vec<u16>> = state.processes.iter()
+        .map(|process| process.queues[&DEFAULT_QUEUE].local_queue.iter().map(|(_, value, _)| *value).collect())
+        .collect();
+    if queues.iter().any(|queue| *queue != queues[0]) {
+        return Some(ViolationKind::Disagreement(queues));
+    }
+
+    match check(&state.history) {
+        CheckResult::Linearizable(_) => None,
+        CheckResult::NotLinearizable(smallest) => Some(ViolationKind::NotLinearizable(smallest)),
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ViolationKind::NotLinearizable(history) => write!(f, "not linearizable:\n{}", history)?,
+            ViolationKind::Disagreement(queues) => {
+                writeln!(f, "ranks disagree on the queue:")?;
+                for (rank, queue) in queues.iter().enumerate() {
+                    writeln!(f, "  rank {}: {:?}", rank, queue)?;
+                }
+            }
+            ViolationKind::Stuck(ops) => writeln!(f, "ops {:?} never returned", ops)?,
+        }
+        write!(f, "schedule: {:?}", self.schedule)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every run to the end without sleep sets, (terminals, violations)
+    fn naive(state: &Simulator<u16>) -> (usize, usize) {
+        let enabled = state.enabled();
+        if enabled.is_empty() {
+            return (1, check_terminal(state).is_some() as usize);
+        }
+        enabled.into_iter()
+            .map(|action| {
+                let mut next = state.clone();
+                next.step(action);
+                naive(&next)
+            })
+            .fold((0, 0), |(t, v), (nt, nv)| (t + nt, v + nv))
+    }
+
+    fn simulator(workload: &[Vec<QueueCall<u16>>]) -> Simulator<u16> {
+        let mut simulator = Simulator::new(0, workload.to_vec());
+        simulator.set_verbose(false);
+        simulator
+    }
+
+    /// A dequeue running alongside another rank's enqueue of an empty queue. Some orders let it
+    /// return ⊥ after its own rank's enqueue went through, a violation the explorer has to find
+    fn racing_dequeue() -> Vec<Vec<QueueCall<u16>>> {
+        vec![vec![QueueCall::Enqueue(1)], vec![QueueCall::Enqueue(2), QueueCall::Dequeue]]
+    }
+
+    /// Sleep sets only skip runs that reorder independent actions: fewer terminals than running
+    /// every order, but a violation in any of them is found too
+    #[test]
+    fn exhaustive_on_two_ranks() {
+        let workloads = [
+            vec![vec![QueueCall::Enqueue(1)], vec![QueueCall::Enqueue(2)]],
+            vec![vec![QueueCall::Enqueue(1)], vec![QueueCall::Dequeue]],
+            vec![vec![QueueCall::EnqueueMany(vec![1, 2])], vec![QueueCall::DequeueUpTo(2)]],
+            racing_dequeue(),
+        ];
+        for workload in workloads {
+            let exploration = explore(workload.clone(), usize::MAX);
+            let (terminals, violations) = naive(&simulator(&workload));
+            assert!(exploration.terminals > 1 && exploration.terminals <= terminals, "{:?}", workload);
+            assert_eq!(exploration.violations.is_empty(), violations == 0, "{:?}", workload);
+        }
+
+        let exploration = explore(vec![vec![QueueCall::Enqueue(1)], vec![QueueCall::Enqueue(2)]], usize::MAX);
+        assert!(exploration.violations.is_empty());
+        assert!(!explore(racing_dequeue(), 1).violations.is_empty(), "the racing dequeue never went wrong");
+    }
+
+    /// The schedule of a violation leads to the same terminal state again, which is what
+    /// explore --out relies on to trace it
+    #[test]
+    fn violations_replay() {
+        let workload = racing_dequeue();
+        let violations = explore(workload.clone(), 5).violations;
+        assert_eq!(violations.len(), 5);
+        for violation in violations {
+            let mut replay = simulator(&workload);
+            replay.run_schedule(&violation.schedule);
+            assert!(replay.enabled().is_empty());
+            let kind = check_terminal(&replay).expect("replay ends without the violation");
+            assert_eq!(Violation { kind, schedule: replay.schedule }.to_string(), violation.to_string());
+        }
+    }
+}
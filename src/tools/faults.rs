@@ -0,0 +1,213 @@
+use std::thread;
+use std::time::Duration;
+use mpi::Rank;
+use crate::util::message_structs::QueueOpReq;
+use crate::util::payload::Payload;
+use crate::tools::rng::SplitMix64;
+use crate::util::transport::{ Transport, TransportError };
+
+/// How often messages get delayed, reordered or duplicated, all probabilities are per message
+#[derive(Debug, Clone, Default)]
+pub struct FaultPolicy {
+    pub delay: f64,
+    pub max_delay: Duration, // delayed sends sleep up to this long first
+    pub reorder: f64, // held back so the next message to the same receiver can overtake it
+    pub duplicate: f64, // sent twice
+    pub seed: u64,
+}
+
+impl FaultPolicy {
+    pub(crate) fn is_none(&self) -> bool {
+        self.delay == 0.0 && self.reorder == 0.0 && self.duplicate == 0.0
+    }
+}
+
+/// What the fault layer did so far
+#[derive(Debug, Clone, Default)]
+pub struct FaultStats {
+    pub delayed: usize,
+    pub reordered: usize, // held back messages that were overtaken, not every one held
+    pub duplicated: usize,
+}
+
+/// try_receive calls a held back message sits out when nothing overtakes it, then it goes
+/// anyway so a rank that only polls doesnt keep it forever
+const HOLD_POLLS: usize = 16;
+
+/// Wraps the send path of another transport and messes with it according to a FaultPolicy.
+/// Held back messages go out before this rank receives or waits at a barrier, so lockstep
+/// code cant deadlock on a message that never left. Polling only lets them go after
+/// HOLD_POLLS tries, so a later send has time to overtake them
+pub struct FaultyTransport<T: Payload, C: Transport<T>> {
+    inner: C,
+    policy: FaultPolicy,
+    rng: SplitMix64,
+    held: Vec<(QueueOpReq<T>, usize)>, // messages waiting for the next send to their receiver, with the polls left until they go anyway
+    stats: FaultStats,
+}
+
+impl<T: Payload, C: Transport<T>> FaultyTransport<T, C> {
+    pub(crate) fn new(inner: C, policy: FaultPolicy) -> Self {
+        let rng = SplitMix64::new(policy.seed ^ (inner.rank() as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        Self {
+            inner,
+            policy,
+            rng,
+            held: Vec::new(),
+            stats: FaultStats::default(),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> &FaultStats {
+        &self.stats
+    }
+
+    fn flush(&mut self) -> Result<(), TransportError> {
+        for (op, _) in std::mem::take(&mut self.held) {
+            self.inner.send(&op)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Payload, C: Transport<T>> Transport<T> for FaultyTransport<T, C> {
+    fn rank(&self) -> Rank {
+        self.inner.rank()
+    }
+
+    fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    fn send(&mut self, op: &QueueOpReq<T>) -> Result<(), TransportError> {
+        if self.rng.chance(self.policy.delay) {
+            self.stats.delayed += 1;
+            let nanos = self.policy.max_delay.as_nanos() as u64;
+            thread::sleep(Duration::from_nanos(self.rng.next_u64() % (nanos + 1)));
+        }
+
+        let (waiting, held): (Vec<_>, Vec<_>) = std::mem::take(&mut self.held).into_iter()
+            .partition(|(held, _)| held.receiver == op.receiver);
+        self.held = held;
+
+        if self.rng.chance(self.policy.reorder) {
+            self.held.push((op.clone(), HOLD_POLLS)); // what waited goes first, nothing overtook it
+        } else {
+            self.inner.send(op)?;
+            if self.rng.chance(self.policy.duplicate) {
+                self.stats.duplicated += 1;
+                self.inner.send(op)?;
+            }
+            self.stats.reordered += waiting.len();
+        }
+        for (held, _) in waiting {
+            self.inner.send(&held)?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, source: Rank) -> Result<QueueOpReq<T>, TransportError> {
+        self.flush()?;
+        self.inner.receive(source)
+    }
+
+    fn try_receive(&mut self) -> Result<Option<QueueOpReq<T>>, TransportError> {
+        let (due, held): (Vec<_>, Vec<_>) = std::mem::take(&mut self.held).into_iter()
+            .map(|(op, polls)| (op, polls - 1))
+            .partition(|(_, polls)| *polls == 0);
+        self.held = held;
+        for (op, _) in due {
+            self.inner.send(&op)?;
+        }
+        self.inner.try_receive()
+    }
+
+    fn barrier(&mut self) -> Result<(), TransportError> {
+        self.flush()?;
+        self.inner.barrier()
+    }
+}
+
+impl<T: Payload, C: Transport<T>> Drop for FaultyTransport<T, C> {
+    fn drop(&mut self) {
+        let _ = self.flush(); // a rank that stops right after sending still gets its last messages out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use super::*;
+    use crate::util::history::{ QueueCall, QueueResult };
+    use crate::util::message_structs::{ OpId, VectorClock, DEFAULT_QUEUE };
+    use crate::util::process::{ DuplicatePolicy, Process };
+    use crate::util::protocol::Message;
+    use crate::util::transport::ChannelTransport;
+
+    fn policy() -> FaultPolicy {
+        FaultPolicy { reorder: 0.3, duplicate: 0.2, seed: 7, ..FaultPolicy::default() }
+    }
+
+    /// Messages held back while the sender keeps polling still get overtaken, and the copies
+    /// and the new order are what the receiver sees
+    #[test]
+    fn reorders_and_duplicates_past_polls() {
+        let mut mesh = ChannelTransport::<u32>::mesh(2);
+        let mut receiver = mesh.pop().unwrap();
+        let mut sender = FaultyTransport::new(mesh.pop().unwrap(), policy());
+        for seq in 1..=200 {
+            let op = OpId { queue: DEFAULT_QUEUE, rank: 0, seq };
+            sender.send(&Message::DeqReq { op, up_to: 0, ts: VectorClock::new(2) }.encode(0, 1, &VectorClock::new(2))).unwrap();
+            assert!(sender.try_receive().unwrap().is_none());
+        }
+        let stats = sender.stats().clone();
+        drop(sender);
+
+        let mut got = Vec::new();
+        while let Some(op) = receiver.try_receive().unwrap() {
+            got.push(op.op.seq);
+        }
+        let overtaken = got.windows(2).filter(|pair| pair[0] > pair[1]).count();
+        assert!(stats.reordered > 0 && stats.duplicated > 0, "{:?}", stats);
+        assert_eq!(overtaken, stats.reordered);
+        assert_eq!(got.len(), 200 + stats.duplicated);
+        got.sort();
+        got.dedup();
+        assert_eq!(got, (1..=200).collect::<Vec<_>>());
+    }
+
+    /// Rank 0 has all its enqueues in flight at once over faulty channels, the duplicates are
+    /// dropped where they arrive and the items still come out in order
+    #[test]
+    fn queue_tolerates_faults() {
+        let threads: Vec<_> = ChannelTransport::<u32>::mesh(3).into_iter()
+            .map(|transport| thread::spawn(move || {
+                let mut process = Process::initialize(FaultyTransport::new(transport, policy()));
+                process.verbose = false;
+                process.on_duplicate = DuplicatePolicy::Report;
+                let mut results = Vec::new();
+                if process.index == 0 {
+                    let ids: Vec<_> = (1..=20).map(|value| process.invoke(DEFAULT_QUEUE, QueueCall::Enqueue(value)).unwrap()).collect();
+                    for id in ids {
+                        assert_eq!(process.wait(id).unwrap(), QueueResult::Enqueued);
+                    }
+                    for _ in 0..=20 {
+                        let id = process.invoke(DEFAULT_QUEUE, QueueCall::Dequeue).unwrap();
+                        results.push(process.wait(id).unwrap());
+                    }
+                }
+                process.shutdown().unwrap();
+                (results, process.transport.stats().clone(), process.duplicates.len())
+            }))
+            .collect();
+        let runs: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+
+        let mut expected: Vec<_> = (1..=20).map(|value| QueueResult::Dequeued(Some(value))).collect();
+        expected.push(QueueResult::Dequeued(None));
+        assert_eq!(runs[0].0, expected);
+        assert!(runs.iter().any(|(_, stats, _)| stats.reordered > 0), "nothing was reordered");
+        let duplicated: usize = runs.iter().map(|(_, stats, _)| stats.duplicated).sum();
+        let dropped: usize = runs.iter().map(|(_, _, dropped)| dropped).sum();
+        assert!(dropped > 0 && dropped <= duplicated, "dropped {} of {} duplicates", dropped, duplicated); // copies can come after a rank stopped
+    }
+}
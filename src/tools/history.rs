@@ -0,0 +1,113 @@
+use std::fmt;
+use mpi::Rank;
+use crate::util::history::{ QueueCall, QueueResult };
+use crate::util::payload::Payload;
+use crate::tools::rng::SplitMix64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HistoryEvent<T: Payload> {
+    Invoke { op: usize, rank: Rank, call: QueueCall<T> },
+    Return { op: usize, result: QueueResult<T> },
+}
+
+/// Invocations and responses in the order they happened, op ids are handed out by invoke
+#[derive(Debug, Clone, Default)]
+pub struct History<T: Payload> {
+    pub events: Vec<HistoryEvent<T>>,
+    next_op: usize,
+}
+
+impl<T: Payload> History<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            events: Vec::new(),
+            next_op: 0,
+        }
+    }
+
+    /// Builds a history from already recorded events, op ids are taken as they are
+    pub(crate) fn from_events(events: Vec<HistoryEvent<T>>) -> Self {
+        let next_op = events.iter()
+            .map(|event| match event {
+                HistoryEvent::Invoke { op, .. } | HistoryEvent::Return { op, .. } => op + 1,
+            })
+            .max()
+            .unwrap_or(0);
+
+        Self { events, next_op }
+    }
+
+    pub(crate) fn invoke(&mut self, rank: Rank, call: QueueCall<T>) -> usize {
+        let op = self.next_op;
+        self.next_op += 1;
+        self.events.push(HistoryEvent::Invoke { op, rank, call });
+        op
+    }
+
+    pub(crate) fn respond(&mut self, op: usize, result: QueueResult<T>) {
+        self.events.push(HistoryEvent::Return { op, result });
+    }
+
+    /// Ops that were invoked but never got a response
+    pub(crate) fn pending(&self) -> Vec<usize> {
+        let mut pending = Vec::new();
+        for event in &self.events {
+            match event {
+                HistoryEvent::Invoke { op, .. } => pending.push(*op),
+                HistoryEvent::Return { op, .. } => pending.retain(|pending_op| pending_op != op),
+            }
+        }
+
+        pending
+    }
+}
+
+/// ops random calls as (invoker, call), roughly half of them enqueues and a quarter of either
+/// kind a batch of 2 or 3. Values count up from 1 so every enqueue can be told apart in the history
+pub(crate) fn random_workload(seed: u64, num_procs: usize, ops: usize) -> Vec<(Rank, QueueCall<u16>)> {
+    let mut rng = SplitMix64::new(seed);
+    let mut next_value = 0u16;
+    (0..ops)
+        .map(|_| {
+            let invoker = rng.below(num_procs) as Rank;
+            let enqueue = rng.below(2) == 0;
+            let batch = (rng.below(4) == 0).then(|| 2 + rng.below(2));
+            let call = match (enqueue, batch) {
+                (true, None) => {
+                    next_value += 1;
+                    QueueCall::Enqueue(next_value)
+                }
+                (true, Some(len)) => QueueCall::EnqueueMany((0..len).map(|_| {
+                    next_value += 1;
+                    next_value
+                }).collect()),
+                (false, None) => QueueCall::Dequeue,
+                (false, Some(up_to)) => QueueCall::DequeueUpTo(up_to),
+            };
+            (invoker, call)
+        })
+        .collect()
+}
+
+impl<T: Payload> fmt::Display for History<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for event in &self.events {
+            match event {
+                HistoryEvent::Invoke { op, rank, call } => match call {
+                    QueueCall::Enqueue(value) => writeln!(f, "op {} rank {} invoke enqueue {:?}", op, rank, value)?,
+                    QueueCall::Dequeue => writeln!(f, "op {} rank {} invoke dequeue", op, rank)?,
+                    QueueCall::EnqueueMany(values) => writeln!(f, "op {} rank {} invoke enqueue {:?}", op, rank, values)?,
+                    QueueCall::DequeueUpTo(k) => writeln!(f, "op {} rank {} invoke dequeue up to {}", op, rank, k)?,
+                },
+                HistoryEvent::Return { op, result } => match result {
+                    QueueResult::Enqueued => writeln!(f, "op {} returns ok", op)?,
+                    QueueResult::Dequeued(Some(value)) => writeln!(f, "op {} returns {:?}", op, value)?,
+                    QueueResult::Dequeued(None) => writeln!(f, "op {} returns ⊥", op)?,
+                    QueueResult::DequeuedMany(values) => writeln!(f, "op {} returns {:?}", op, values)?,
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
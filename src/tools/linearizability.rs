@@ -0,0 +1,378 @@
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hash;
+use crate::util::history::{ QueueCall, QueueResult };
+use crate::tools::history::{ History, HistoryEvent };
+use crate::util::payload::Payload;
+
+#[derive(Debug, Clone)]
+pub enum CheckResult<T: Payload> {
+    /// op ids in an order that is a legal sequential FIFO execution, pending ops that had to
+    /// take effect are included, the ones that didnt are left out
+    Linearizable(Vec<usize>),
+    /// Smallest history we could cut the failure down to, it still has no linearization
+    NotLinearizable(History<T>),
+}
+
+/// Wing & Gong style search for a linearization of a queue history, with the Lowe / Porcupine
+/// trick of remembering (linearized ops, queue contents) states that already failed
+pub fn check<T: Payload + Eq + Hash>(history: &History<T>) -> CheckResult<T> {
+    match linearize(history) {
+        Some(order) => CheckResult::Linearizable(order),
+        None => CheckResult::NotLinearizable(minimize(history)),
+    }
+}
+
+struct Entry<T: Payload> {
+    op: usize,
+    call: QueueCall<T>,
+    result: Option<QueueResult<T>>, // None while pending
+    invoked: usize, // position of the invoke event
+    returned: usize, // position of the return event, usize::MAX while pending
+}
+
+struct Search<T: Payload> {
+    entries: Vec<Entry<T>>,
+    failed: HashSet<(Vec<bool>, Vec<T>)>,
+    order: Vec<usize>,
+}
+
+pub(crate) fn linearize<T: Payload + Eq + Hash>(history: &History<T>) -> Option<Vec<usize>> {
+    let mut entries: Vec<Entry<T>> = Vec::new();
+    for (position, event) in history.events.iter().enumerate() {
+        match event {
+            HistoryEvent::Invoke { op, call, .. } => entries.push(Entry {
+                op: *op,
+                call: call.clone(),
+                result: None,
+                invoked: position,
+                returned: usize::MAX,
+            }),
+            HistoryEvent::Return { op, result } => {
+                if let Some(entry) = entries.iter_mut().find(|entry| entry.op == *op) {
+                    entry.result = Some(result.clone());
+                    entry.returned = position;
+                }
+            }
+        }
+    }
+
+    let mut done = vec![false; entries.len()];
+    let mut search = Search { entries, failed: HashSet::new(), order: Vec::new() };
+    if search.search(&mut done, &mut VecDeque::new()) {
+        Some(search.order)
+    } else {
+        None
+    }
+}
+
+impl<T: Payload + Eq + Hash> Search<T> {
+    fn search(&mut self, done: &mut Vec<bool>, queue: &mut VecDeque<T>) -> bool {
+        let finished = self.entries.iter().zip(done.iter())
+            .all(|(entry, done)| *done || entry.result.is_none());
+        if finished {
+            return true;
+        }
+        if !self.failed.insert((done.clone(), queue.iter().cloned().collect())) {
+            return false; // been here before and it didnt work out
+        }
+
+        // nothing may be linearized after an op that returned before it was invoked
+        let horizon = self.entries.iter().zip(done.iter())
+            .filter(|(_, done)| !**done)
+            .map(|(entry, _)| entry.returned)
+            .min()
+            .unwrap_or(usize::MAX);
+
+        for i in 0..self.entries.len() {
+            if done[i] || self.entries[i].invoked > horizon {
+                continue;
+            }
+
+            match self.entries[i].call.clone() {
+                QueueCall::Enqueue(value) => {
+                    let legal = matches!(self.entries[i].result, None | Some(QueueResult::Enqueued));
+                    if legal {
+                        queue.push_back(value);
+                        if self.take(i, done, queue) {
+                            return true;
+                        }
+                        queue.pop_back();
+                    }
+                }
+                QueueCall::EnqueueMany(values) => {
+                    let legal = matches!(self.entries[i].result, None | Some(QueueResult::Enqueued));
+                    if legal {
+                        queue.extend(values.iter().cloned());
+                        if self.take(i, done, queue) {
+                            return true;
+                        }
+                        queue.truncate(queue.len() - values.len());
+                    }
+                }
+                QueueCall::Dequeue => {
+                    let front = queue.pop_front();
+                    let legal = match &self.entries[i].result {
+                        None => true, // pending, could have seen anything
+                        Some(QueueResult::Dequeued(value)) => *value == front,
+                        Some(_) => false,
+                    };
+                    if legal && self.take(i, done, queue) {
+                        return true;
+                    }
+                    if let Some(value) = front {
+                        queue.push_front(value);
+                    }
+                }
+                QueueCall::DequeueUpTo(k) => {
+                    let front: Vec<T> = queue.drain(..k.min(queue.len())).collect();
+                    let legal = match &self.entries[i].result {
+                        None => true,
+                        Some(QueueResult::DequeuedMany(values)) => *values == front,
+                        Some(_) => false,
+                    };
+                    if legal && self.take(i, done, queue) {
+                        return true;
+                    }
+                    for value in front.into_iter().rev() {
+                        queue.push_front(value);
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    fn take(&mut self, i: usize, done: &mut Vec<bool>, queue: &mut VecDeque<T>) -> bool {
+        done[i] = true;
+        self.order.push(self.entries[i].op);
+        if self.search(done, queue) {
+            return true;
+        }
+        self.order.pop();
+        done[i] = false;
+        false
+    }
+}
+
+/// Cuts a non linearizable history down: first to the shortest failing prefix, then drops ops
+/// for as long as what is left still fails. An enqueue only goes together with the dequeues that
+/// returned its value, dropping it alone would just swap in a different violation
+fn minimize<T: Payload + Eq + Hash>(history: &History<T>) -> History<T> {
+    // every extension of a non linearizable history is non linearizable, so bisect
+    let mut lo = 0;
+    let mut hi = history.events.len();
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if linearize(&History::from_events(history.events[..mid].to_vec())).is_none() {
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    let mut events = history.events[..hi].to_vec();
+
+    loop {
+        let mut shrunk = false;
+        for group in removable_groups(&events) {
+            let candidate: Vec<HistoryEvent<T>> = events.iter()
+                .filter(|event| !group.contains(&event_op(event)))
+                .cloned()
+                .collect();
+            if linearize(&History::from_events(candidate.clone())).is_none() {
+                events = candidate;
+                shrunk = true;
+                break;
+            }
+        }
+        if !shrunk {
+            break;
+        }
+    }
+
+    History::from_events(events)
+}
+
+fn removable_groups<T: Payload + Eq>(events: &[HistoryEvent<T>]) -> Vec<Vec<usize>> {
+    let mut groups = Vec::new();
+    for event in events {
+        match event {
+            HistoryEvent::Invoke { op, call: QueueCall::Enqueue(value), .. } => {
+                groups.push(with_dequeues(*op, std::slice::from_ref(value), events));
+            }
+            HistoryEvent::Invoke { op, call: QueueCall::EnqueueMany(values), .. } => {
+                groups.push(with_dequeues(*op, values, events));
+            }
+            HistoryEvent::Invoke { op, call: QueueCall::Dequeue | QueueCall::DequeueUpTo(_), .. } => groups.push(vec![*op]),
+            HistoryEvent::Return { .. } => {}
+        }
+    }
+
+    groups
+}
+
+/// The enqueue op of values and every dequeue that returned one of them
+fn with_dequeues<T: Payload + Eq>(op: usize, values: &[T], events: &[HistoryEvent<T>]) -> Vec<usize> {
+    let mut group = vec![op];
+    for other in events {
+        let (deq_op, got) = match other {
+            HistoryEvent::Return { op, result: QueueResult::Dequeued(Some(got)) } => (*op, std::slice::from_ref(got)),
+            HistoryEvent::Return { op, result: QueueResult::DequeuedMany(got) } => (*op, &got[..]),
+            _ => continue,
+        };
+        if got.iter().any(|got| values.contains(got)) {
+            group.push(deq_op);
+        }
+    }
+    group
+}
+
+fn event_op<T: Payload>(event: &HistoryEvent<T>) -> usize {
+    match event {
+        HistoryEvent::Invoke { op, .. } | HistoryEvent::Return { op, .. } => *op,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enq(value: u16) -> QueueCall<u16> {
+        QueueCall::Enqueue(value)
+    }
+
+    fn got(value: Option<u16>) -> QueueResult<u16> {
+        QueueResult::Dequeued(value)
+    }
+
+    /// Every call returns before the next one is invoked
+    fn sequential(ops: Vec<(QueueCall<u16>, QueueResult<u16>)>) -> History<u16> {
+        let mut history = History::new();
+        for (call, result) in ops {
+            let op = history.invoke(0, call);
+            history.respond(op, result);
+        }
+        history
+    }
+
+    fn linearizable(history: &History<u16>) -> bool {
+        matches!(check(history), CheckResult::Linearizable(_))
+    }
+
+    #[test]
+    fn sequential_fifo() {
+        let history = sequential(vec![
+            (enq(1), QueueResult::Enqueued),
+            (enq(2), QueueResult::Enqueued),
+            (QueueCall::Dequeue, got(Some(1))),
+            (QueueCall::Dequeue, got(Some(2))),
+            (QueueCall::Dequeue, got(None)),
+        ]);
+        assert!(matches!(check(&history), CheckResult::Linearizable(order) if order == vec![0, 1, 2, 3, 4]));
+
+        let history = sequential(vec![
+            (enq(1), QueueResult::Enqueued),
+            (enq(2), QueueResult::Enqueued),
+            (QueueCall::Dequeue, got(Some(2))),
+        ]);
+        assert!(!linearizable(&history));
+    }
+
+    /// Overlapping enqueues can take effect in either order
+    #[test]
+    fn concurrent_enqueues() {
+        let mut history = History::new();
+        let first = history.invoke(0, enq(1));
+        let second = history.invoke(1, enq(2));
+        history.respond(first, QueueResult::Enqueued);
+        history.respond(second, QueueResult::Enqueued);
+        let deq = history.invoke(0, QueueCall::Dequeue);
+        history.respond(deq, got(Some(2)));
+        assert!(matches!(check(&history), CheckResult::Linearizable(order) if order == vec![second, first, deq]));
+    }
+
+    /// ⊥ is only right when the queue can have been empty at some point during the dequeue
+    #[test]
+    fn empty_dequeues() {
+        let history = sequential(vec![(enq(1), QueueResult::Enqueued), (QueueCall::Dequeue, got(None))]);
+        assert!(!linearizable(&history));
+
+        let mut history = History::new();
+        let deq = history.invoke(0, QueueCall::Dequeue);
+        let enq_op = history.invoke(1, enq(1));
+        history.respond(enq_op, QueueResult::Enqueued);
+        history.respond(deq, got(None));
+        assert!(linearizable(&history));
+    }
+
+    /// A pending enqueue counts if something dequeued its value, a pending dequeue can be left out
+    #[test]
+    fn pending_ops() {
+        let mut history = History::new();
+        let pending_enq = history.invoke(0, enq(1));
+        let deq = history.invoke(1, QueueCall::Dequeue);
+        history.respond(deq, got(Some(1)));
+        assert!(matches!(check(&history), CheckResult::Linearizable(order) if order == vec![pending_enq, deq]));
+
+        let mut history = sequential(vec![(enq(1), QueueResult::Enqueued)]);
+        let pending_deq = history.invoke(0, QueueCall::Dequeue);
+        let deq = history.invoke(1, QueueCall::Dequeue);
+        history.respond(deq, got(Some(1)));
+        assert!(matches!(check(&history), CheckResult::Linearizable(order) if !order.contains(&pending_deq)));
+
+        let mut history = sequential(vec![(QueueCall::Dequeue, got(Some(7)))]);
+        history.invoke(0, enq(7)); // invoked after the value came out
+        assert!(!linearizable(&history));
+    }
+
+    #[test]
+    fn batches() {
+        let history = sequential(vec![
+            (QueueCall::EnqueueMany(vec![1, 2, 3]), QueueResult::Enqueued),
+            (QueueCall::DequeueUpTo(2), QueueResult::DequeuedMany(vec![1, 2])),
+            (QueueCall::DequeueUpTo(5), QueueResult::DequeuedMany(vec![3])),
+        ]);
+        assert!(linearizable(&history));
+
+        let history = sequential(vec![
+            (QueueCall::EnqueueMany(vec![1, 2]), QueueResult::Enqueued),
+            (QueueCall::DequeueUpTo(2), QueueResult::DequeuedMany(vec![2, 1])),
+        ]);
+        assert!(!linearizable(&history));
+    }
+
+    /// The violation sits between unrelated ops on both sides, minimize cuts it down to the three
+    /// ops it needs and none of them can go
+    #[test]
+    fn minimized_counterexample_is_minimal() {
+        let history = sequential(vec![
+            (enq(5), QueueResult::Enqueued),
+            (QueueCall::Dequeue, got(Some(5))),
+            (enq(1), QueueResult::Enqueued),
+            (enq(6), QueueResult::Enqueued),
+            (enq(2), QueueResult::Enqueued),
+            (QueueCall::Dequeue, got(Some(2))),
+            (QueueCall::Dequeue, got(Some(1))),
+            (enq(3), QueueResult::Enqueued),
+        ]);
+        let CheckResult::NotLinearizable(minimized) = check(&history) else {
+            panic!("history is not linearizable");
+        };
+
+        let calls: Vec<_> = minimized.events.iter()
+            .filter_map(|event| match event {
+                HistoryEvent::Invoke { call, .. } => Some(call.clone()),
+                HistoryEvent::Return { .. } => None,
+            })
+            .collect();
+        assert_eq!(calls.len(), 3, "{}", minimized);
+        assert!(calls.contains(&enq(2)) && calls.contains(&QueueCall::Dequeue), "{}", minimized);
+        for group in removable_groups(&minimized.events) {
+            let smaller: Vec<_> = minimized.events.iter()
+                .filter(|event| !group.contains(&event_op(event)))
+                .cloned()
+                .collect();
+            assert!(linearize(&History::from_events(smaller)).is_some(), "{:?} could go from\n{}", group, minimized);
+        }
+    }
+}
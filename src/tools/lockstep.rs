@@ -0,0 +1,230 @@
+use mpi::Rank;
+use crate::util::error::QueueError;
+use crate::util::message_structs::{ OpId, OpNextAction, QueueOpReq, RankInfo, DEFAULT_QUEUE };
+use crate::util::payload::Payload;
+use crate::util::process::Process;
+use crate::util::protocol::MessageKind;
+use crate::util::transport::Transport;
+
+/// Ops run in lockstep: every rank calls the same step with the same message and only its
+/// sender and receiver act on it, the scenarios and the fixed linearizations are built from these
+impl<T: Payload, C: Transport<T>> Process<T, C> {
+    fn check_rank(&self, rank: Rank) -> Result<(), QueueError> {
+        if rank < 0 || rank as usize >= self.num_procs {
+            return Err(QueueError::InvalidRank { rank, size: self.num_procs });
+        }
+        Ok(())
+    }
+
+    /// Lockstep drivers build every message themselves, one that doesnt decode is a broken
+    /// driver or a broken peer and the step cant go on
+    fn handle_lockstep(&mut self, op: QueueOpReq<T>) -> Result<OpNextAction<T>, QueueError> {
+        Ok(self.handle_queue_op(op)?)
+    }
+
+    pub(crate) fn sync_send_receive(&mut self, op: QueueOpReq<T>) -> Result<OpNextAction<T>, QueueError> {
+        self.check_rank(op.sender)?;
+        self.check_rank(op.receiver)?;
+        if (op.receiver == op.sender)  && self.index == op.sender {
+            if op.message != MessageKind::EnqInvoke && op.message != MessageKind::DeqInvoke {
+                self.trace_send(&op);
+                self.trace_receive(&op);
+            }
+            return self.handle_lockstep(op); // dont need the transport to send to self
+        } else if op.receiver == op.sender { // do nothing
+            return Ok(OpNextAction::default());
+        }
+
+        let mut recv_op = None;
+        if self.index == op.sender {
+            self.trace_send(&op);
+            self.transport.send(&op)?;
+        } else if self.index == op.receiver {
+            let received = self.transport.receive(op.sender)?;
+            self.trace_receive(&received);
+            recv_op = Some(received);
+        }
+
+        self.transport.barrier()?; // All processes reach the barrier
+        if let Some(recv_op) = recv_op {
+            return self.handle_lockstep(recv_op);
+        }
+
+        Ok(OpNextAction::default())
+    }
+
+    pub(crate) fn async_send_receive(&mut self, op: QueueOpReq<T>) -> Result<OpNextAction<T>, QueueError> {
+        self.check_rank(op.sender)?;
+        self.check_rank(op.receiver)?;
+        if (op.receiver == op.sender)  && self.index == op.sender {
+            if op.message != MessageKind::EnqInvoke && op.message != MessageKind::DeqInvoke {
+                self.trace_send(&op);
+                self.trace_receive(&op);
+            }
+            return self.handle_lockstep(op); // dont need the transport to send to self
+        } else if op.receiver == op.sender { // do nothing
+            return Ok(OpNextAction::default());
+        }
+
+        let mut recv_op = None;
+        if self.index == op.sender {
+            self.trace_send(&op);
+            self.transport.send(&op)?;
+        } else if self.index == op.receiver {
+            let received = self.transport.receive(op.sender)?;
+            self.trace_receive(&received);
+            recv_op = Some(received);
+        }
+        if let Some(recv_op) = recv_op {
+            return self.handle_lockstep(recv_op);
+        }
+
+        Ok(OpNextAction::default())
+    }
+
+    pub(crate) fn enqueue(&mut self, invoking: Rank, val: T) -> Result<(), QueueError> {
+        self.check_rank(invoking)?;
+        let mut enq_ts = Default::default();
+        let mut enq_op = OpId::default();
+        if self.index == invoking {
+            let res = self.async_send_receive(QueueOpReq{
+                message: MessageKind::EnqInvoke,
+                value: val.clone(),
+                sender: invoking,
+                receiver: invoking,
+                op: OpId::default(),
+                timestamp: self.clock(DEFAULT_QUEUE),
+                batch: Vec::new(),
+                up_to: 0,
+                info: RankInfo::default(),
+            })?;
+            enq_ts = res.ts;
+            enq_op = res.op;
+        }
+
+        for i in 0..self.num_procs {
+            if i != invoking as usize {
+                if self.index as usize == i { // receiver
+                    let res = self.async_send_receive(QueueOpReq{
+                        message: MessageKind::EnqReq,
+                        value: val.clone(),
+                        sender: invoking,
+                        receiver: i as Rank,
+                        op: enq_op,
+                        timestamp: enq_ts.clone(),
+                        batch: Vec::new(),
+                        up_to: 0,
+                        info: RankInfo::default(),
+                    })?;
+                    enq_ts = res.ts;
+                    enq_op = res.op;
+                }else {
+                    self.async_send_receive(QueueOpReq{
+                        message: MessageKind::EnqReq,
+                        value: val.clone(),
+                        sender: invoking,
+                        receiver: i as Rank,
+                        op: enq_op,
+                        timestamp: enq_ts.clone(),
+                        batch: Vec::new(),
+                        up_to: 0,
+                        info: RankInfo::default(),
+                    })?;
+                }
+            }
+        }
+
+        for i in 0..self.num_procs {
+            if i != invoking as usize {
+                self.async_send_receive(QueueOpReq{
+                    message: MessageKind::EnqAck,
+                    value: T::default(),
+                    sender: i as Rank,
+                    receiver: invoking,
+                    op: enq_op,
+                    timestamp: enq_ts.clone(),
+                    batch: Vec::new(),
+                    up_to: 0,
+                    info: RankInfo::default(),
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn dequeue(&mut self, invoking: Rank) -> Result<Option<T>, QueueError> {
+        self.check_rank(invoking)?;
+        let mut message_buffer = MessageKind::default();
+        let res = self.async_send_receive(QueueOpReq{
+            message: MessageKind::DeqInvoke,
+            value: T::default(),
+            sender: invoking,
+            receiver: invoking,
+            op: OpId::default(),
+            timestamp: self.clock(DEFAULT_QUEUE),
+            batch: Vec::new(),
+            up_to: 0,
+            info: RankInfo::default(),
+        })?;
+        let mut deq_ts = res.ts;
+        let mut deq_op = res.op;
+
+
+        for i in 0..self.num_procs {
+            if self.index as usize == i {
+                 let res = self.async_send_receive(QueueOpReq{
+                    message: MessageKind::DeqReq,
+                    value: T::default(),
+                    sender: invoking,
+                    receiver: i as Rank,
+                    op: deq_op,
+                    timestamp: deq_ts.clone(),
+                    batch: Vec::new(),
+                    up_to: 0,
+                    info: RankInfo::default(),
+                })?;
+                deq_ts = res.ts;
+                deq_op = res.op;
+                message_buffer = res.message.next_or(message_buffer);
+
+            } else {
+                self.async_send_receive(QueueOpReq{
+                    message: MessageKind::DeqReq,
+                    value: T::default(),
+                    sender: invoking,
+                    receiver: i as Rank,
+                    op: deq_op,
+                    timestamp: deq_ts.clone(),
+                    batch: Vec::new(),
+                    up_to: 0,
+                    info: RankInfo::default(),
+                })?;
+            }
+        }
+
+        let mut ret_val = OpNextAction::default();
+
+        for i in 0..self.num_procs {
+            for j in 0..self.num_procs {
+                let res = self.async_send_receive(QueueOpReq{
+                    message: message_buffer,
+                    value: T::default(),
+                    sender: i as Rank,
+                    receiver: j as Rank,
+                    op: deq_op,
+                    timestamp: deq_ts.clone(),
+                    batch: Vec::new(),
+                    up_to: 0,
+                    info: RankInfo::default(),
+                })?;
+
+                match res.value{
+                    Some(_) => ret_val = res,
+                    _=>{}
+                }
+            }
+        }
+
+        Ok(ret_val.value)
+    }
+}
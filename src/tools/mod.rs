@@ -0,0 +1,11 @@
+pub mod cli;
+pub(crate) mod rng;
+pub(crate) mod simulator;
+pub(crate) mod history;
+pub(crate) mod linearizability;
+pub(crate) mod traces;
+pub(crate) mod scenario;
+pub(crate) mod explorer;
+pub(crate) mod faults;
+pub(crate) mod execution_linearizer;
+pub(crate) mod lockstep;
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use mpi::Rank;
+use crate::tools::execution_linearizer::{ DequeueFixedLinearization, EnqueueFixedLinearization };
+use crate::util::message_structs::DEFAULT_QUEUE;
+use crate::util::payload::Payload;
+use crate::util::process::Process;
+use crate::util::transport::Transport;
+
+/// A fixed linearization written as text, one statement per line or separated by ';'.
+/// Anything after '#' is a comment. E<name> statements drive an EnqueueFixedLinearization,
+/// D<name> ones a DequeueFixedLinearization, the name has to be invoked before it is used:
+///
+///   E1 invoke@0 value=1        enq_invoke(0, 1)
+///   E1 req->1                  enq_req(1)
+///   E1 ack<-1                  enq_ack(1)
+///   D1 invoke@1                deq_invoke(1)
+///   D1 req->2                  deq_req(2)
+///   D1 safe_unsafe 2->0        safe_unsafe(2, 0)
+///   D1 safe_unsafe_all from 2  safe_unsafe_all(2)
+///   enqueue@1 value=16         whole enqueue in lockstep
+///   dequeue@0                  whole dequeue in lockstep, prints what it got
+///   print_queue                prints the local queue of every rank
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScenarioStep<T: Payload> {
+    EnqInvoke { name: String, invoker: Rank, value: T },
+    EnqReq { name: String, receiver: Rank },
+    EnqAck { name: String, sender: Rank },
+    DeqInvoke { name: String, invoker: Rank },
+    DeqReq { name: String, receiver: Rank },
+    SafeUnsafe { name: String, sender: Rank, receiver: Rank },
+    SafeUnsafeAll { name: String, sender: Rank },
+    Enqueue { invoker: Rank, value: T },
+    Dequeue { invoker: Rank },
+    PrintQueue,
+}
+
+#[derive(Debug, Clone)]
+pub struct Scenario<T: Payload> {
+    steps: Vec<(usize, ScenarioStep<T>)>, // line the step came from, for error messages
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScenarioError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScenarioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "scenario line {}: {}", self.line, self.message)
+    }
+}
+impl std::error::Error for ScenarioError {}
+
+fn error<R>(line: usize, message: String) -> Result<R, ScenarioError> {
+    Err(ScenarioError { line, message })
+}
+
+impl<T: Payload + FromStr> Scenario<T> {
+    pub(crate) fn parse(text: &str) -> Result<Self, ScenarioError> {
+        let mut steps = Vec::new();
+        let mut invoked: Vec<String> = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line_number = number + 1;
+            let line = line.split('#').next().unwrap_or("");
+            for statement in line.split(';') {
+                let words: Vec<&str> = statement.split_whitespace().collect();
+                if words.is_empty() {
+                    continue;
+                }
+                let step = parse_step(&words, line_number)?;
+                match &step {
+                    ScenarioStep::EnqInvoke { name, .. } | ScenarioStep::DeqInvoke { name, .. } => {
+                        invoked.push(name.clone());
+                    }
+                    ScenarioStep::EnqReq { name, .. } | ScenarioStep::EnqAck { name, .. }
+                    | ScenarioStep::DeqReq { name, .. } | ScenarioStep::SafeUnsafe { name, .. }
+                    | ScenarioStep::SafeUnsafeAll { name, .. } => {
+                        if !invoked.contains(name) {
+                            return error(line_number, format!("{} is used before it is invoked", name));
+                        }
+                    }
+                    _ => {}
+                }
+                steps.push((line_number, step));
+            }
+        }
+
+        Ok(Self { steps })
+    }
+}
+
+fn parse_step<T: Payload + FromStr>(words: &[&str], line: usize) -> Result<ScenarioStep<T>, ScenarioError> {
+    match words {
+        ["print_queue"] => return Ok(ScenarioStep::PrintQueue),
+        [op, rest @ ..] if op.starts_with("enqueue@") => {
+            let invoker = parse_rank(&op["enqueue@".len()..], line)?;
+            let value = parse_value(rest, line)?;
+            return Ok(ScenarioStep::Enqueue { invoker, value });
+        }
+        [op] if op.starts_with("dequeue@") => {
+            let invoker = parse_rank(&op["dequeue@".len()..], line)?;
+            return Ok(ScenarioStep::Dequeue { invoker });
+        }
+        _ => {}
+    }
+
+    let (name, action, args) = match words {
+        [name, action, args @ ..] => (name.to_string(), *action, args),
+        _ => return error(line, format!("cant make sense of '{}'", words.join(" "))),
+    };
+    let is_enqueue = name.starts_with('E');
+    if !is_enqueue && !name.starts_with('D') {
+        return error(line, format!("operation names start with E or D, got {}", name));
+    }
+
+    if let Some(rank) = action.strip_prefix("invoke@") {
+        let invoker = parse_rank(rank, line)?;
+        return if is_enqueue {
+            Ok(ScenarioStep::EnqInvoke { name, invoker, value: parse_value(args, line)? })
+        } else if args.is_empty() {
+            Ok(ScenarioStep::DeqInvoke { name, invoker })
+        } else {
+            error(line, "dequeues dont take a value".to_string())
+        };
+    }
+
+    match (is_enqueue, action, args) {
+        (_, req, []) if req.starts_with("req->") => {
+            let receiver = parse_rank(&req["req->".len()..], line)?;
+            if is_enqueue {
+                Ok(ScenarioStep::EnqReq { name, receiver })
+            } else {
+                Ok(ScenarioStep::DeqReq { name, receiver })
+            }
+        }
+        (true, ack, []) if ack.starts_with("ack<-") => {
+            let sender = parse_rank(&ack["ack<-".len()..], line)?;
+            Ok(ScenarioStep::EnqAck { name, sender })
+        }
+        (false, "safe_unsafe", [route]) => match route.split_once("->") {
+            Some((sender, receiver)) => Ok(ScenarioStep::SafeUnsafe {
+                name,
+                sender: parse_rank(sender, line)?,
+                receiver: parse_rank(receiver, line)?,
+            }),
+            None => error(line, format!("expected <sender>-><receiver>, got {}", route)),
+        },
+        (false, "safe_unsafe_all", ["from", sender]) => {
+            Ok(ScenarioStep::SafeUnsafeAll { name, sender: parse_rank(sender, line)? })
+        }
+        _ => error(line, format!("{} cant do '{}'", name, words[1..].join(" "))),
+    }
+}
+
+fn parse_rank(word: &str, line: usize) -> Result<Rank, ScenarioError> {
+    match word.parse::<Rank>() {
+        Ok(rank) if rank >= 0 => Ok(rank),
+        _ => error(line, format!("'{}' is not a rank", word)),
+    }
+}
+
+fn parse_value<T: FromStr>(args: &[&str], line: usize) -> Result<T, ScenarioError> {
+    match args {
+        [arg] if arg.starts_with("value=") => arg["value=".len()..].parse::<T>()
+            .or_else(|_| error(line, format!("'{}' is not a valid value", &arg["value=".len()..]))),
+        _ => error(line, "expected value=<value>".to_string()),
+    }
+}
+
+impl<T: Payload> Scenario<T> {
+    /// Every rank has to exist, checked before anything is sent since a bad rank halfway
+    /// through would leave the other processes waiting forever
+    pub(crate) fn validate(&self, num_procs: usize) -> Result<(), ScenarioError> {
+        for (line, step) in &self.steps {
+            let ranks = match step {
+                ScenarioStep::EnqInvoke { invoker, .. } | ScenarioStep::DeqInvoke { invoker, .. }
+                | ScenarioStep::Enqueue { invoker, .. } | ScenarioStep::Dequeue { invoker } => vec![*invoker],
+                ScenarioStep::EnqReq { receiver, .. } | ScenarioStep::DeqReq { receiver, .. } => vec![*receiver],
+                ScenarioStep::EnqAck { sender, .. } | ScenarioStep::SafeUnsafeAll { sender, .. } => vec![*sender],
+                ScenarioStep::SafeUnsafe { sender, receiver, .. } => vec![*sender, *receiver],
+                ScenarioStep::PrintQueue => vec![],
+            };
+            for rank in ranks {
+                if rank as usize >= num_procs {
+                    return error(*line, format!("rank {} but only {} processes", rank, num_procs));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drives process through the scenario, every rank runs the same scenario in lockstep
+    pub(crate) fn run<C: Transport<T>>(&self, process: &mut Process<T, C>) -> Result<(), ScenarioError> {
+        self.validate(process.num_procs)?;
+
+        let mut enqueues: HashMap<String, EnqueueFixedLinearization<T>> = HashMap::new();
+        let mut dequeues: HashMap<String, DequeueFixedLinearization> = HashMap::new();
+
+        for (line, step) in &self.steps {
+            let done = match step.clone() {
+                ScenarioStep::EnqInvoke { name, invoker, value } => {
+                    let mut enqueue = EnqueueFixedLinearization::new(); // a name invoked again is a new op
+                    let done = enqueue.enq_invoke(invoker, value, process);
+                    enqueues.insert(name, enqueue);
+                    done
+                }
+                ScenarioStep::EnqReq { name, receiver } => enqueues.get_mut(&name).unwrap().enq_req(receiver, process),
+                ScenarioStep::EnqAck { name, sender } => enqueues.get_mut(&name).unwrap().enq_ack(sender, process),
+                ScenarioStep::DeqInvoke { name, invoker } => {
+                    let mut dequeue = DequeueFixedLinearization::new();
+                    let done = dequeue.deq_invoke(invoker, process);
+                    dequeues.insert(name, dequeue);
+                    done
+                }
+                ScenarioStep::DeqReq { name, receiver } => dequeues.get_mut(&name).unwrap().deq_req(receiver, process),
+                ScenarioStep::SafeUnsafe { name, sender, receiver } => {
+                    dequeues.get_mut(&name).unwrap().safe_unsafe(sender, receiver, process)
+                }
+                ScenarioStep::SafeUnsafeAll { name, sender } => {
+                    dequeues.get_mut(&name).unwrap().safe_unsafe_all(sender, process)
+                }
+                ScenarioStep::Enqueue { invoker, value } => process.enqueue(invoker, value),
+                ScenarioStep::Dequeue { invoker } => process.dequeue(invoker).map(|value| match value {
+                    Some(value) => println!("Got value: {:?}", value),
+                    None => println!("Got value: ⊥"),
+                }),
+                ScenarioStep::PrintQueue => {
+                    println!("{} {:?}", process.index, process.queues[&DEFAULT_QUEUE].local_queue);
+                    Ok(())
+                }
+            };
+            done.or_else(|e| error(*line, e.to_string()))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Scenario<u16>, ScenarioError> {
+        Scenario::parse(text)
+    }
+
+    /// Line the error points at and whether its message mentions what
+    fn rejects(text: &str, line: usize, what: &str) {
+        let e = parse(text).expect_err(text);
+        assert_eq!(e.line, line, "{}", e);
+        assert!(e.message.contains(what), "'{}' doesnt mention '{}'", e.message, what);
+    }
+
+    #[test]
+    fn parses_every_statement() {
+        let scenario = parse("
+            # comment
+            E1 invoke@0 value=7; E1 req->1 # trailing comment
+            E1 ack<-1
+            D1 invoke@1; D1 req->0; D1 safe_unsafe 0->1; D1 safe_unsafe_all from 1
+            enqueue@1 value=3;;
+            dequeue@0
+            print_queue
+        ").unwrap();
+        let steps: Vec<_> = scenario.steps.iter().map(|(_, step)| step.clone()).collect();
+        let name = |name: &str| name.to_string();
+        assert_eq!(steps, vec![
+            ScenarioStep::EnqInvoke { name: name("E1"), invoker: 0, value: 7 },
+            ScenarioStep::EnqReq { name: name("E1"), receiver: 1 },
+            ScenarioStep::EnqAck { name: name("E1"), sender: 1 },
+            ScenarioStep::DeqInvoke { name: name("D1"), invoker: 1 },
+            ScenarioStep::DeqReq { name: name("D1"), receiver: 0 },
+            ScenarioStep::SafeUnsafe { name: name("D1"), sender: 0, receiver: 1 },
+            ScenarioStep::SafeUnsafeAll { name: name("D1"), sender: 1 },
+            ScenarioStep::Enqueue { invoker: 1, value: 3 },
+            ScenarioStep::Dequeue { invoker: 0 },
+            ScenarioStep::PrintQueue,
+        ]);
+        assert_eq!(scenario.steps[0].0, 3);
+        assert_eq!(scenario.steps[9].0, 8);
+    }
+
+    #[test]
+    fn default_scenario_needs_three_ranks() {
+        let scenario = parse(include_str!("../../scenarios/fixed_linearization.scn")).unwrap();
+        assert!(scenario.validate(3).is_ok());
+        assert!(scenario.validate(2).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_statements() {
+        rejects("print_queue\nE1 req->1", 2, "before it is invoked");
+        rejects("X1 invoke@0 value=1", 1, "start with E or D");
+        rejects("E1 invoke@-1 value=1", 1, "not a rank");
+        rejects("E1 invoke@0", 1, "value=");
+        rejects("E1 invoke@0 value=big", 1, "not a valid value");
+        rejects("D1 invoke@0 value=1", 1, "dont take a value");
+        rejects("D1 invoke@0\nD1 safe_unsafe 0-1", 2, "<sender>-><receiver>");
+        rejects("E1 invoke@0 value=1; E1 safe_unsafe 0->1", 1, "cant do");
+        rejects("E1", 1, "cant make sense");
+        rejects("enqueue@x value=1", 1, "not a rank");
+    }
+
+    #[test]
+    fn validate_names_the_line_with_a_missing_rank() {
+        let scenario = parse("enqueue@0 value=1\nD1 invoke@0; D1 safe_unsafe 1->4").unwrap();
+        let e = scenario.validate(4).unwrap_err();
+        assert_eq!(e.line, 2);
+        assert!(e.message.contains("rank 4"), "{}", e);
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::{BTreeMap, VecDeque};
+use mpi::Rank;
+use crate::tools::faults::FaultPolicy;
+use crate::util::history::{ QueueCall, QueueResult };
+use crate::tools::history::History;
+use crate::util::message_structs::{ OpId, QueueOpReq, DEFAULT_QUEUE };
+use crate::util::payload::Payload;
+use crate::util::process::Process;
+use crate::tools::rng::SplitMix64;
+use crate::util::transport::{ Transport, TransportError };
+
+/// Stand in transport for simulated processes, the simulator moves every message itself
+#[derive(Debug, Clone)]
+pub struct SimTransport {
+    rank: Rank,
+    size: usize,
+}
+
+impl<T: Payload> Transport<T> for SimTransport {
+    fn rank(&self) -> Rank {
+        self.rank
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn send(&mut self, _op: &QueueOpReq<T>) -> Result<(), TransportError> {
+        panic!("simulated processes dont send, the simulator routes their messages");
+    }
+
+    fn receive(&mut self, _source: Rank) -> Result<QueueOpReq<T>, TransportError> {
+        panic!("simulated processes dont receive, the simulator routes their messages");
+    }
+
+    fn try_receive(&mut self) -> Result<Option<QueueOpReq<T>>, TransportError> {
+        panic!("simulated processes dont receive, the simulator routes their messages");
+    }
+
+    fn barrier(&mut self) -> Result<(), TransportError> {
+        Ok(())
+    }
+}
+
+/// One scheduling decision, the seed picks which of the enabled ones happens next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimAction {
+    Invoke(Rank), // rank starts its next operation
+    Deliver { from: Rank, to: Rank }, // oldest message on the from -> to channel arrives
+}
+
+#[derive(Debug, Clone)]
+struct Outstanding {
+    op: usize, // op id in the history
+    id: OpId, // what the process calls it
+}
+
+/// Runs num_procs processes in one OS process. Channels are FIFO per (sender, receiver) like MPI,
+/// everything else about the interleaving is decided by the seed, so a run can be replayed
+/// exactly by building the simulator again with the same seed and workload
+#[derive(Clone)]
+pub struct Simulator<T: Payload> {
+    pub(crate) processes: Vec<Process<T, SimTransport>>,
+    channels: BTreeMap<(Rank, Rank), VecDeque<QueueOpReq<T>>>,
+    workload: Vec<VecDeque<QueueCall<T>>>, // operations each rank still has to invoke, in order
+    outstanding: Vec<Vec<Outstanding>>, // operations in flight per rank
+    pipeline: usize, // how many operations a rank may have in flight at once
+    rng: SplitMix64,
+    faults: FaultPolicy, // reorder and duplicate apply per channel, delay is what scheduling does anyway
+    fault_rng: SplitMix64, // separate so a policy doesnt change which schedule a seed gives
+    pub(crate) schedule: Vec<SimAction>, // every step taken so far
+    pub(crate) history: History<T>, // what the clients saw, for the linearizability checker
+}
+
+impl<T: Payload> Simulator<T> {
+    /// workload[i] is the list of operations rank i invokes, one after the other
+    pub(crate) fn new(seed: u64, workload: Vec<Vec<QueueCall<T>>>) -> Self {
+        let num_procs = workload.len();
+        assert!(num_procs >= 2, "the protocol needs at least 2 processes");
+
+        Self {
+            processes: (0..num_procs)
+                .map(|rank| Process::initialize(SimTransport { rank: rank as Rank, size: num_procs }))
+                .collect(),
+            channels: BTreeMap::new(),
+            workload: workload.into_iter().map(VecDeque::from).collect(),
+            outstanding: vec![Vec::new(); num_procs],
+            pipeline: 1,
+            rng: SplitMix64::new(seed),
+            faults: FaultPolicy::default(),
+            fault_rng: SplitMix64::new(0),
+            schedule: Vec::new(),
+            history: History::new(),
+        }
+    }
+
+    pub(crate) fn with_faults(mut self, policy: FaultPolicy) -> Self {
+        self.fault_rng = SplitMix64::new(policy.seed);
+        self.faults = policy;
+        self
+    }
+
+    /// Lets every rank invoke up to depth operations before the first one finishes
+    pub(crate) fn with_pipeline(mut self, depth: usize) -> Self {
+        self.pipeline = depth.max(1);
+        self
+    }
+
+    pub(crate) fn set_verbose(&mut self, verbose: bool) {
+        for process in &mut self.processes {
+            process.verbose = verbose;
+        }
+    }
+
+    pub(crate) fn num_procs(&self) -> usize {
+        self.processes.len()
+    }
+
+    /// Every action that could happen next, in a fixed order so the seed alone decides the pick
+    pub(crate) fn enabled(&self) -> Vec<SimAction> {
+        let mut actions = Vec::new();
+        for rank in 0..self.num_procs() {
+            if self.outstanding[rank].len() < self.pipeline && !self.workload[rank].is_empty() {
+                actions.push(SimAction::Invoke(rank as Rank));
+            }
+        }
+        for (&(from, to), channel) in self.channels.iter() {
+            if !channel.is_empty() {
+                actions.push(SimAction::Deliver { from, to });
+            }
+        }
+
+        actions
+    }
+
+    /// Runs until nothing is enabled anymore, every choice comes from the seed
+    pub(crate) fn run(&mut self) {
+        loop {
+            let actions = self.enabled();
+            if actions.is_empty() {
+                break;
+            }
+            let action = actions[self.rng.below(actions.len())];
+            self.step(action);
+        }
+    }
+
+    /// Replays a recorded schedule, panics if it asks for something that isnt enabled
+    pub(crate) fn run_schedule(&mut self, schedule: &[SimAction]) {
+        for action in schedule {
+            assert!(self.enabled().contains(action), "{:?} is not enabled", action);
+            self.step(*action);
+        }
+    }
+
+    pub(crate) fn step(&mut self, action: SimAction) {
+        self.schedule.push(action);
+
+        match action {
+            SimAction::Invoke(rank) => self.invoke(rank),
+            SimAction::Deliver { from, to } => self.deliver(from, to),
+        }
+    }
+
+    fn invoke(&mut self, rank: Rank) {
+        let call = self.workload[rank as usize].pop_front()
+            .expect("invoke on a rank with nothing left to do");
+        let (id, messages) = self.processes[rank as usize].start(DEFAULT_QUEUE, &call)
+            .expect("workloads dont have empty batches");
+        for op in messages {
+            self.post(op);
+        }
+
+        let op = self.history.invoke(rank, call);
+        self.outstanding[rank as usize].push(Outstanding { op, id });
+    }
+
+    fn deliver(&mut self, from: Rank, to: Rank) {
+        let channel = self.channels.get_mut(&(from, to)).expect("deliver on an empty channel");
+        let op = channel.pop_front().expect("deliver on an empty channel");
+        if channel.is_empty() {
+            self.channels.remove(&(from, to));
+        }
+
+        self.processes[to as usize].trace_receive(&op);
+        let replies = self.processes[to as usize].react(op)
+            .expect("simulated ranks only send what their own encode made");
+        for reply in replies {
+            self.post(reply);
+        }
+
+        while let Some((id, result)) = self.processes[to as usize].completed.pop_front() {
+            self.complete(to, id, result);
+        }
+    }
+
+    fn complete(&mut self, rank: Rank, id: OpId, result: QueueResult<T>) {
+        let outstanding = &mut self.outstanding[rank as usize];
+        let pos = outstanding.iter().position(|outstanding| outstanding.id == id)
+            .expect("completion without an outstanding operation");
+        let outstanding = outstanding.remove(pos);
+        self.history.respond(outstanding.op, result);
+    }
+
+    fn post(&mut self, op: QueueOpReq<T>) {
+        let copies = if self.fault_rng.chance(self.faults.duplicate) { 2 } else { 1 };
+        for _ in 0..copies {
+            self.processes[op.sender as usize].trace_send(&op);
+            let channel = self.channels.entry((op.sender, op.receiver)).or_default();
+            if !channel.is_empty() && self.fault_rng.chance(self.faults.reorder) {
+                let overtaken = self.fault_rng.below(channel.len());
+                channel.insert(overtaken, op.clone()); // jumps ahead of everything from there on
+            } else {
+                channel.push_back(op.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::history::HistoryEvent;
+
+    /// One rank enqueues and dequeues while the others only answer, whatever order the seed
+    /// delivers in it gets its items back in order and every replica ends up empty
+    #[test]
+    fn fifo_in_any_delivery_order() {
+        for seed in 0..50 {
+            let workload = vec![
+                vec![QueueCall::Enqueue(1u16), QueueCall::Enqueue(2), QueueCall::Dequeue, QueueCall::Dequeue, QueueCall::Dequeue],
+                Vec::new(),
+                Vec::new(),
+            ];
+            let mut simulator = Simulator::new(seed, workload);
+            simulator.set_verbose(false);
+            simulator.run();
+
+            let results: Vec<_> = simulator.history.events.iter()
+                .filter_map(|event| match event {
+                    HistoryEvent::Return { result: QueueResult::Dequeued(value), .. } => Some(*value),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(results, vec![Some(1), Some(2), None], "seed {}", seed);
+            assert!(simulator.history.pending().is_empty(), "seed {}", seed);
+            for process in &simulator.processes {
+                assert!(process.queues[&DEFAULT_QUEUE].local_queue.is_empty(), "seed {}", seed);
+            }
+        }
+    }
+
+    /// A seed and workload give the same run every time, another seed delivers in another order
+    #[test]
+    fn seeds_replay() {
+        let workload = vec![
+            vec![QueueCall::Enqueue(1u16), QueueCall::Dequeue],
+            vec![QueueCall::Enqueue(2), QueueCall::Dequeue],
+            vec![QueueCall::Enqueue(3)],
+        ];
+        let run = |seed| {
+            let mut simulator = Simulator::new(seed, workload.clone());
+            simulator.set_verbose(false);
+            simulator.run();
+            (simulator.schedule, simulator.history.events)
+        };
+
+        let (schedule, events) = run(7);
+        assert_eq!(run(7), (schedule.clone(), events));
+        assert_ne!(run(8).0, schedule);
+    }
+
+    /// Every rank forgets a dequeue's confirmation list once it is through, however the
+    /// SAFEs and UNSAFEs of several dequeues interleave
+    #[test]
+    fn confirmation_lists_dont_outlive_their_dequeues() {
+        for seed in 0..50 {
+            let workload = vec![
+                vec![QueueCall::Enqueue(1u16), QueueCall::Enqueue(2), QueueCall::Dequeue, QueueCall::Dequeue],
+                vec![QueueCall::Dequeue],
+                Vec::new(),
+            ];
+            let mut simulator = Simulator::new(seed, workload).with_pipeline(2);
+            simulator.set_verbose(false);
+            simulator.run();
+
+            assert!(simulator.history.pending().is_empty(), "seed {}", seed);
+            for process in &simulator.processes {
+                let lists = &process.queues[&DEFAULT_QUEUE].lists;
+                assert!(lists.is_empty(), "seed {}: rank {} kept {} lists", seed, process.index, lists.len());
+            }
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use mpi::Rank;
+use crate::util::history::{ QueueCall, QueueResult };
+use crate::util::message_structs::{ OpId, QueueId, DEFAULT_QUEUE };
+use crate::util::payload::Payload;
+use crate::util::process::Process;
+use crate::util::protocol::MessageKind;
+use crate::util::trace::{ TraceEvent, TraceEventKind, TraceRecorder };
+use crate::util::transport::Transport;
+use crate::tools::history::History;
+
+impl<T: Payload, C: Transport<T>> Process<T, C> {
+    pub(crate) fn trace_to(&mut self, recorder: TraceRecorder) {
+        self.trace = Some(recorder);
+    }
+
+    /// Why the trace stopped early, if it did
+    pub(crate) fn trace_error(&self) -> Option<io::Error> {
+        self.trace.as_ref().and_then(TraceRecorder::take_error)
+    }
+}
+
+pub(crate) fn read_trace(path: &Path) -> io::Result<Vec<TraceEvent>> {
+    let mut events = Vec::new();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = serde_json::from_str(&line).map_err(|e| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}:{}: {}", path.display(), number + 1, e),
+        ))?;
+        events.push(event);
+    }
+
+    Ok(events)
+}
+
+pub(crate) fn write_trace(path: &Path, events: &[TraceEvent]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for event in events {
+        serde_json::to_writer(&mut out, event)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+/// (sender, receiver, queue, kind, ts) of a message, how a receive finds its send. Clocks are per
+/// queue so two queues can send the same kind at the same ts
+type MessageKey = (Rank, Rank, QueueId, String, Vec<i32>);
+
+/// Merges per rank traces into one causally ordered trace: each rank keeps its own order and a
+/// receive only comes after its send. Among the events that could go next the one with the
+/// smallest clock sum wins, then the lower rank, so the output doesnt depend on file order.
+/// Receives missing an op id get the one from their send
+pub(crate) fn merge_traces(traces: Vec<Vec<TraceEvent>>) -> Vec<TraceEvent> {
+    let mut traces: Vec<VecDeque<TraceEvent>> = traces.into_iter().map(VecDeque::from).collect();
+    let mut sent: HashMap<MessageKey, VecDeque<Option<OpId>>> = HashMap::new();
+    let mut merged = Vec::new();
+
+    loop {
+        let mut best: Option<(i64, Rank, usize)> = None;
+        let mut stuck: Option<(i64, Rank, usize)> = None;
+        for (i, trace) in traces.iter().enumerate() {
+            let Some(head) = trace.front() else { continue };
+            let key = (head.clock.iter().map(|&c| c as i64).sum::<i64>(), head.rank, i);
+            let ready = head.event != TraceEventKind::Receive
+                || sent.get(&message_key(head)).is_some_and(|ops| !ops.is_empty());
+            if ready {
+                if best.map_or(true, |best| key < best) {
+                    best = Some(key);
+                }
+            } else if stuck.map_or(true, |stuck| key < stuck) {
+                stuck = Some(key);
+            }
+        }
+
+        let i = match (best, stuck) {
+            (Some((_, _, i)), _) => i,
+            (None, Some((_, _, i))) => {
+                // a receive whose send is in none of the files, dont lose it
+                eprintln!("trace for rank {} has a receive without a matching send", traces[i][0].rank);
+                i
+            }
+            (None, None) => break,
+        };
+
+        let mut event = traces[i].pop_front().unwrap();
+        match event.event {
+            TraceEventKind::Send => {
+                sent.entry(message_key(&event)).or_default().push_back(event.op);
+            }
+            TraceEventKind::Receive => {
+                let send_op = sent.get_mut(&message_key(&event)).and_then(|ops| ops.pop_front()).flatten();
+                if event.op.is_none() {
+                    event.op = send_op;
+                }
+            }
+            _ => {}
+        }
+        merged.push(event);
+    }
+
+    merged
+}
+
+fn message_key(event: &TraceEvent) -> MessageKey {
+    let peer = event.peer.unwrap_or(event.rank);
+    let (sender, receiver) = match event.event {
+        TraceEventKind::Receive => (peer, event.rank),
+        _ => (event.rank, peer),
+    };
+
+    let queue = event.op.map_or(DEFAULT_QUEUE, |op| op.queue);
+    (sender, receiver, queue, event.kind.clone(), event.ts.clone().unwrap_or_default())
+}
+
+/// merge-traces <out> <rank files...>
+pub(crate) fn merge_trace_files(out: &Path, inputs: &[PathBuf]) -> io::Result<()> {
+    let traces = inputs.iter()
+        .map(|path| read_trace(path))
+        .collect::<io::Result<Vec<_>>>()?;
+    write_trace(out, &merge_traces(traces))
+}
+
+/// Queues with ops in the trace, each is checked on its own
+pub(crate) fn trace_queues(events: &[TraceEvent]) -> BTreeSet<QueueId> {
+    events.iter()
+        .filter(|event| event.event == TraceEventKind::Invoke)
+        .filter_map(|event| event.op.map(|op| op.queue))
+        .collect()
+}
+
+/// Client view of one queue in a trace: invocations and responses in trace order with the values
+/// as they were recorded. A merged trace is ordered like some run of the protocol could have
+/// gone, so checking it is checking that run
+pub(crate) fn trace_history(events: &[TraceEvent], queue: QueueId) -> History<String> {
+    let enqueue = MessageKind::EnqInvoke.to_string();
+    let mut history = History::new();
+    let mut ops: HashMap<OpId, (usize, bool)> = HashMap::new(); // history op and whether it is a batch dequeue
+
+    for event in events.iter().filter(|event| event.op.is_some_and(|op| op.queue == queue)) {
+        match (event.event, event.op) {
+            (TraceEventKind::Invoke, Some(op)) => {
+                let call = match (event.kind == enqueue, &event.batch, event.up_to) {
+                    (true, Some(values), _) => QueueCall::EnqueueMany(values.clone()),
+                    (true, None, _) => QueueCall::Enqueue(event.value.clone().unwrap_or_default()),
+                    (false, _, Some(up_to)) => QueueCall::DequeueUpTo(up_to as usize),
+                    (false, _, None) => QueueCall::Dequeue,
+                };
+                let batch_dequeue = matches!(call, QueueCall::DequeueUpTo(_));
+                ops.insert(op, (history.invoke(event.rank, call), batch_dequeue));
+            }
+            (TraceEventKind::Response, Some(op)) => {
+                if let Some(&(id, batch_dequeue)) = ops.get(&op) {
+                    let result = if event.kind == enqueue {
+                        QueueResult::Enqueued
+                    } else if batch_dequeue {
+                        QueueResult::DequeuedMany(event.batch.clone().unwrap_or_default())
+                    } else {
+                        QueueResult::Dequeued(event.value.clone())
+                    };
+                    history.respond(id, result);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    history
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+    use crate::tools::history::HistoryEvent;
+    use crate::tools::simulator::Simulator;
+    use crate::util::message_structs::queue_id;
+    use crate::util::trace::trace_file;
+
+    fn op(queue: QueueId, rank: Rank, seq: i32) -> Option<OpId> {
+        Some(OpId { queue, rank, seq })
+    }
+
+    fn event(rank: Rank, event: TraceEventKind, op: Option<OpId>, kind: MessageKind, peer: Option<Rank>, clock: Vec<i32>) -> TraceEvent {
+        TraceEvent { rank, event, op, kind: kind.to_string(), peer, value: None, ts: Some(vec![1, 0]), clock, batch: None, up_to: None }
+    }
+
+    /// Rank 1's receive has the smaller clock but waits for rank 0's send, and gets its op id
+    /// from it when it has none
+    #[test]
+    fn receives_come_after_their_send() {
+        let enq = op(DEFAULT_QUEUE, 0, 1);
+        let rank_0 = vec![
+            event(0, TraceEventKind::Invoke, enq, MessageKind::EnqInvoke, None, vec![5, 0]),
+            event(0, TraceEventKind::Send, enq, MessageKind::EnqReq, Some(1), vec![6, 0]),
+        ];
+        let rank_1 = vec![event(1, TraceEventKind::Receive, None, MessageKind::EnqReq, Some(0), vec![0, 0])];
+
+        let merged = merge_traces(vec![rank_1, rank_0]);
+        let kinds: Vec<_> = merged.iter().map(|event| (event.rank, event.event)).collect();
+        assert_eq!(kinds, vec![(0, TraceEventKind::Invoke), (0, TraceEventKind::Send), (1, TraceEventKind::Receive)]);
+        assert_eq!(merged[2].op, enq);
+    }
+
+    /// Two queues send the same kind at the same ts, a receive on one doesnt match the send on
+    /// the other
+    #[test]
+    fn messages_are_keyed_per_queue() {
+        let (a, b) = (op(queue_id("a"), 0, 1), op(queue_id("b"), 0, 1));
+        let rank_0 = vec![
+            event(0, TraceEventKind::Send, a, MessageKind::DeqReq, Some(1), vec![1, 0]),
+            event(0, TraceEventKind::Send, b, MessageKind::DeqReq, Some(1), vec![9, 0]),
+        ];
+        let rank_1 = vec![
+            event(1, TraceEventKind::Receive, b, MessageKind::DeqReq, Some(0), vec![0, 1]),
+            event(1, TraceEventKind::Receive, a, MessageKind::DeqReq, Some(0), vec![0, 2]),
+        ];
+
+        let merged = merge_traces(vec![rank_0, rank_1]);
+        let at = |event: TraceEventKind, op| merged.iter().position(|e| e.event == event && e.op == op).unwrap();
+        assert!(at(TraceEventKind::Receive, b) > at(TraceEventKind::Send, b));
+        assert!(at(TraceEventKind::Receive, a) > at(TraceEventKind::Send, a));
+    }
+
+    /// A receive whose send is in no trace is kept and the ranks after it still merge
+    #[test]
+    fn receives_without_a_send_arent_lost() {
+        let deq = op(DEFAULT_QUEUE, 1, 1);
+        let rank_0 = vec![event(0, TraceEventKind::Receive, None, MessageKind::Safe, Some(1), vec![0, 0])];
+        let rank_1 = vec![
+            event(1, TraceEventKind::Invoke, deq, MessageKind::DeqInvoke, None, vec![0, 1]),
+            event(1, TraceEventKind::Response, deq, MessageKind::DeqInvoke, None, vec![0, 3]),
+        ];
+
+        let merged = merge_traces(vec![rank_0, rank_1]);
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged.last().unwrap().event, TraceEventKind::Receive);
+        assert_eq!(merged.last().unwrap().op, None);
+    }
+
+    /// Only the invokes and responses of the queue asked for make it into the history, with
+    /// the values as they were recorded
+    #[test]
+    fn history_of_one_queue() {
+        let other = queue_id("other");
+        let (enq, deq) = (op(DEFAULT_QUEUE, 0, 1), op(DEFAULT_QUEUE, 1, 1));
+        let with_value = |mut event: TraceEvent, value: &str| {
+            event.value = Some(value.to_string());
+            event
+        };
+        let events = vec![
+            with_value(event(0, TraceEventKind::Invoke, enq, MessageKind::EnqInvoke, None, vec![1, 0]), "7"),
+            event(1, TraceEventKind::Invoke, op(other, 1, 1), MessageKind::DeqInvoke, None, vec![0, 1]),
+            event(0, TraceEventKind::Send, enq, MessageKind::EnqReq, Some(1), vec![1, 0]),
+            with_value(event(0, TraceEventKind::Response, enq, MessageKind::EnqInvoke, None, vec![1, 0]), "7"),
+            event(1, TraceEventKind::Invoke, deq, MessageKind::DeqInvoke, None, vec![1, 1]),
+            with_value(event(1, TraceEventKind::Response, deq, MessageKind::DeqInvoke, None, vec![1, 1]), "7"),
+        ];
+
+        assert_eq!(trace_queues(&events), BTreeSet::from([DEFAULT_QUEUE, other]));
+        assert_eq!(trace_history(&events, DEFAULT_QUEUE).events, vec![
+            HistoryEvent::Invoke { op: 0, rank: 0, call: QueueCall::Enqueue("7".to_string()) },
+            HistoryEvent::Return { op: 0, result: QueueResult::Enqueued },
+            HistoryEvent::Invoke { op: 1, rank: 1, call: QueueCall::Dequeue },
+            HistoryEvent::Return { op: 1, result: QueueResult::Dequeued(Some("7".to_string())) },
+        ]);
+    }
+
+    /// Batch ops come back out of a recorded run as the batches they were, so a traced run
+    /// checks the same history the simulator saw
+    #[test]
+    fn batches_survive_tracing() {
+        let workload = vec![
+            vec![QueueCall::EnqueueMany(vec![1u16, 2, 3]), QueueCall::Enqueue(4), QueueCall::DequeueUpTo(2), QueueCall::Dequeue, QueueCall::DequeueUpTo(3)],
+            Vec::new(),
+        ];
+        let dir = std::env::temp_dir().join(format!("batches_survive_tracing-{}", std::process::id()));
+        let mut simulator = Simulator::new(3, workload);
+        simulator.set_verbose(false);
+        for (rank, process) in simulator.processes.iter_mut().enumerate() {
+            process.trace_to(TraceRecorder::to_dir(rank as Rank, &dir).unwrap());
+        }
+        simulator.run();
+        for process in &simulator.processes {
+            assert!(process.trace_error().is_none());
+        }
+        let traces = (0..2).map(|rank| read_trace(&trace_file(&dir, rank)).unwrap()).collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let strings = |values: &[u16]| values.iter().map(|value| value.to_string()).collect::<Vec<_>>();
+        let expected: Vec<HistoryEvent<String>> = simulator.history.events.iter()
+            .map(|event| match event.clone() {
+                HistoryEvent::Invoke { op, rank, call } => HistoryEvent::Invoke { op, rank, call: match call {
+                    QueueCall::Enqueue(value) => QueueCall::Enqueue(value.to_string()),
+                    QueueCall::EnqueueMany(values) => QueueCall::EnqueueMany(strings(&values)),
+                    QueueCall::Dequeue => QueueCall::Dequeue,
+                    QueueCall::DequeueUpTo(up_to) => QueueCall::DequeueUpTo(up_to),
+                }},
+                HistoryEvent::Return { op, result } => HistoryEvent::Return { op, result: match result {
+                    QueueResult::Enqueued => QueueResult::Enqueued,
+                    QueueResult::Dequeued(value) => QueueResult::Dequeued(value.map(|value| value.to_string())),
+                    QueueResult::DequeuedMany(values) => QueueResult::DequeuedMany(strings(&values)),
+                }},
+            })
+            .collect();
+        assert_eq!(trace_history(&merge_traces(traces), DEFAULT_QUEUE).events, expected);
+    }
+}
@@ -1,5 +1,4 @@
 use std::cmp::Ordering;
-use crate::util::constants::NUM_PROCS;
 
 #[derive(Debug, PartialEq)]
 pub enum ComparisonResult {
@@ -19,7 +18,7 @@ pub enum ComparisonResult {
 /// # Returns
 ///
 /// * `ComparisonResult` - An enum representing the comparison result:
-pub fn compare_ts(vec_i: &[i32; NUM_PROCS], vec_j: &[i32; NUM_PROCS]) -> ComparisonResult {
+pub fn compare_ts(vec_i: &[i32], vec_j: &[i32]) -> ComparisonResult {
     let mut strictly_less = true;
     let mut less = false;
 
@@ -53,7 +52,7 @@ pub fn compare_ts(vec_i: &[i32; NUM_PROCS], vec_j: &[i32; NUM_PROCS]) -> Compari
 /// # Returns
 ///
 /// * `Ordering` - A very standard ordering idk, no shot I write these comments for the whole project
-pub fn compare_ts_ord(vec_i: &[i32; NUM_PROCS], vec_j: &[i32; NUM_PROCS]) -> Ordering {
+pub fn compare_ts_ord(vec_i: &[i32], vec_j: &[i32]) -> Ordering {
     for (ts_i, ts_j) in vec_i.iter().zip(vec_j.iter()) {
         match ts_i.cmp(ts_j) {
             Ordering::Less => return Ordering::Less,
@@ -65,7 +64,7 @@ pub fn compare_ts_ord(vec_i: &[i32; NUM_PROCS], vec_j: &[i32; NUM_PROCS]) -> Ord
     Ordering::Equal // All elements are equal
 }
 
-pub fn contains_all_zeros(vec_i: &[i32; NUM_PROCS]) -> bool {
+pub fn contains_all_zeros(vec_i: &[i32]) -> bool {
     for ts_i in vec_i.iter() {
         if *ts_i != 0 {
             return false;
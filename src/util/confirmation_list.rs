@@ -1,22 +1,24 @@
-use crate::util::constants::NUM_PROCS;
-use crate::util::process::Process;
-use std::fmt::Write;
+use crate::util::message_structs::OpId;
 
 #[derive(Debug, Clone)]
 pub struct ConfirmationList {
-    pub(crate) response_list: [i32; NUM_PROCS],
-    pub(crate) ts: [i32; NUM_PROCS],
-    pub(crate) handled: bool
+    pub(crate) op: OpId, // dequeue the list is for
+    pub(crate) response_list: Vec<i32>,
+    pub(crate) ts: Vec<i32>,
+    pub(crate) handled: bool,
+    pub(crate) up_to: u32, // items a batch dequeue takes, 0 for a plain one
 }
 
 impl ConfirmationList {
-    pub(crate) fn new(dequeue_ts: [i32; NUM_PROCS]) -> Self {
-        let mut response_list = [0; NUM_PROCS]; // Initialize response_list with n zeros
+    pub(crate) fn new(op: OpId, dequeue_ts: Vec<i32>, up_to: u32) -> Self {
+        let response_list = vec![0; dequeue_ts.len()]; // Initialize response_list with n zeros
 
         Self {
+            op,
             response_list,
             ts: dequeue_ts,
-            handled: false
+            handled: false,
+            up_to,
         }
     }
 }
@@ -42,19 +44,3 @@ pub fn update_unsafes(lists: &mut Vec<ConfirmationList>, row: usize) {
         }
     }
 }
-
-pub fn print_confirmation_lists(process: &Process) {
-    let mut output = String::new();
-
-    writeln!(output, "ConfirmationList for process {}", process.index).unwrap();
-    for confirmation_list in &process.lists {
-        writeln!(
-            output,
-            "list {:?} ts {:?}",
-            confirmation_list.response_list, confirmation_list.ts
-        )
-            .unwrap();
-    }
-
-    println!("{}", output);
-}
\ No newline at end of file
@@ -0,0 +1,75 @@
+use std::fmt;
+use mpi::Rank;
+use crate::util::message_structs::QueueId;
+use crate::util::protocol::ProtocolError;
+use crate::util::transport::TransportError;
+
+/// Everything a queue operation can fail with instead of taking the job down
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueueError {
+    InvalidRank { rank: Rank, size: usize }, // named a rank outside the world
+    WorldSizeMismatch { expected: usize, actual: usize }, // someone runs with a different number of ranks
+    VersionMismatch { rank: Rank, ours: i32, theirs: i32 }, // rank speaks another PROTOCOL_VERSION
+    PayloadMismatch { rank: Rank, ours: u32, theirs: u32 }, // rank queues another payload type, by fingerprint
+    Transport(TransportError), // a message couldnt go out or come in
+    Protocol(ProtocolError), // a message that breaks the protocol
+    QueueIdClash { opened: String, name: String, id: QueueId }, // two names hash to the same queue
+    EmptyBatch, // a batch call with nothing in it, on the wire it would read as a single op
+    Stopped, // the driver of an async queue isnt running anymore
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::InvalidRank { rank, size } => write!(f, "rank {} is outside a world of {}", rank, size),
+            QueueError::WorldSizeMismatch { expected, actual } => {
+                write!(f, "expected a world of {} ranks but got {}", expected, actual)
+            }
+            QueueError::VersionMismatch { rank, ours, theirs } => {
+                write!(f, "rank {} speaks protocol version {}, this one {}", rank, theirs, ours)
+            }
+            QueueError::PayloadMismatch { rank, ours, theirs } => {
+                write!(f, "rank {} queues another payload type (fingerprint {:08x}, ours {:08x})", rank, theirs, ours)
+            }
+            QueueError::Transport(e) => write!(f, "transport failed: {}", e),
+            QueueError::Protocol(e) => write!(f, "protocol violation: {}", e),
+            QueueError::QueueIdClash { opened, name, id } => {
+                write!(f, "queues {:?} and {:?} have the same id {}", opened, name, id)
+            }
+            QueueError::EmptyBatch => write!(f, "a batch needs at least one item"),
+            QueueError::Stopped => write!(f, "the queue's driver stopped"),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            QueueError::Transport(e) => Some(e),
+            QueueError::Protocol(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A message that came in but doesnt read is the sender breaking the protocol, not the
+/// transport failing
+impl From<TransportError> for QueueError {
+    fn from(e: TransportError) -> Self {
+        match e {
+            TransportError::Garbled(_, e) => e.into(),
+            e => QueueError::Transport(e),
+        }
+    }
+}
+
+/// A clock with the wrong number of entries comes from a rank that thinks the world is another
+/// size, that gets its own variant so it doesnt read like a garbled message
+impl From<ProtocolError> for QueueError {
+    fn from(e: ProtocolError) -> Self {
+        match e {
+            ProtocolError::BadTimestamp { len, expected } => QueueError::WorldSizeMismatch { expected, actual: len },
+            e => QueueError::Protocol(e),
+        }
+    }
+}
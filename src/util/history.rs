@@ -0,0 +1,19 @@
+use crate::util::payload::Payload;
+
+/// What a client asked the queue to do
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueueCall<T: Payload> {
+    Enqueue(T),
+    Dequeue,
+    EnqueueMany(Vec<T>), // all of them in this order, as one op
+    DequeueUpTo(usize), // as many as there are up to this, as one op
+}
+
+/// What the queue answered, a dequeue on an empty queue gets None (⊥) and a batch dequeue
+/// gets fewer items than it asked for or none
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueueResult<T: Payload> {
+    Enqueued,
+    Dequeued(Option<T>),
+    DequeuedMany(Vec<T>),
+}
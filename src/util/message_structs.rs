@@ -1,28 +1,17 @@
-use std::mem::{size_of};
-use mpi::datatype::{AsDatatype, Equivalence, UserDatatype};
-use mpi::{Address, Count, Rank};
-use crate::util::numeric_encodings::req_encoding_to_string;
-use crate::util::constants::NUM_PROCS;
+use mpi::traits::Equivalence;
+use mpi::Rank;
+use crate::util::payload::Payload;
+use crate::util::protocol::{ MessageKind, ProtocolError };
+use serde::{Deserialize, Serialize};
 
 
-#[derive(Copy, Clone, Default)]
-pub(crate) struct VectorClock(pub [i32; NUM_PROCS]);
+/// Vector timestamp with one entry per rank, sized from the world when the process starts
+#[derive(Clone, Default, PartialEq)]
+pub struct VectorClock(pub Vec<i32>);
 
-unsafe impl Equivalence for VectorClock {
-    type Out = UserDatatype;
-
-    fn equivalent_datatype() -> Self::Out {
-        let mut displacements = Vec::with_capacity(NUM_PROCS);
-        let size_of_i32 = size_of::<i32>() as Address;
-        for i in 0..NUM_PROCS {
-            displacements.push(i as Address * size_of_i32);
-        }
-
-        UserDatatype::structured(
-            &[1; NUM_PROCS],
-            &displacements,
-            &[i32::equivalent_datatype(); NUM_PROCS],
-        )
+impl VectorClock {
+    pub(crate) fn new(num_procs: usize) -> Self {
+        VectorClock(vec![0; num_procs])
     }
 }
 
@@ -36,237 +25,222 @@ fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 }
 }
 
-#[derive(Debug, Default, Clone, Copy)]
-pub struct OpNextAction {
-    pub message: u16,
-    pub value: Option<u16>,
-    pub invoker: Rank,
-    pub ts: VectorClock,
-}
-
-#[derive(Clone, Copy)]
-pub struct EnqReq {
-    pub message: u16,
-    pub value: u16,
-    pub rank: Rank,
-    pub timestamp: VectorClock,
-}
-
-unsafe impl Equivalence for EnqReq {
-    type Out = UserDatatype;
-
-    fn equivalent_datatype() -> Self::Out {
-        let ts_equivalent = VectorClock::equivalent_datatype(); //Store to use ref
+/// Which of the logical queues a message belongs to. Every rank derives it from the name the
+/// same way, so nobody has to agree on ids beforehand
+pub type QueueId = u32;
 
-        let counts = [
-            1 as Count, // One EnqReq
-            1 as Count, // One u16 for message
-            1 as Count, // One u8 for value
-            1 as Count, // One Rank
-            1 as Count, // One VectorClock
-        ];
+/// The queue you get without naming one, traces from before there were names are all on it
+pub const DEFAULT_QUEUE: QueueId = 0;
 
-        let displacements = [ // Define memory offsets
-            0 as Address, // Start at 0
-            size_of::<u16>() as Address,
-            size_of::<u16>() as Address,
-            size_of::<Rank>() as Address,
-            size_of::<u16>() as Address + size_of::<u16>()
-                as Address + size_of::<Rank>() as Address, // Offset struct
-        ];
-
-        let types = [
-            Count::equivalent_datatype(),
-            u16::equivalent_datatype(),
-            u16::equivalent_datatype(),
-            Rank::equivalent_datatype(),
-            ts_equivalent.as_ref(), // use temporary reference for lifetime requirements
-        ];
-
-        UserDatatype::structured(&counts, &displacements, &types)
+/// FNV-1a of the name, the empty name is the default queue and no other name maps to it
+pub(crate) fn queue_id(name: &str) -> QueueId {
+    if name.is_empty() {
+        return DEFAULT_QUEUE;
     }
+    fnv1a(name.as_bytes()).max(1)
 }
 
-impl std::fmt::Debug for EnqReq {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{{ message: {}, value: {}, rank: {}, ts: {:?} }}",
-            req_encoding_to_string(self.message), self.value, self.rank, self.timestamp
-        )
-    }
+/// 32 bit FNV-1a, the same on every rank whatever it runs on
+pub(crate) fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5u32, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
 }
 
-pub struct DeqReq {
-    pub message: u16,
+/// Names an operation: the queue, the invoking rank and its own clock entry for that queue right
+/// after invoking. Every message carries one, so nothing has to be matched up by timestamp and
+/// messages of different queues never get mixed up
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OpId {
+    #[serde(default)]
+    pub queue: QueueId,
     pub rank: Rank,
-    pub timestamp: VectorClock
+    pub seq: i32,
 }
 
-unsafe impl Equivalence for DeqReq {
-    type Out = UserDatatype;
-
-    fn equivalent_datatype() -> Self::Out {
-        let ts_equivalent = VectorClock::equivalent_datatype();
-
-        let counts = [
-            1 as Count, // One DeqReq
-            1 as Count, // One u16
-            1 as Count, // One Rank
-            1 as Count // One VectorClock
-        ];
-
-        let displacements = [ // Define memory offsets
-            0 as Address, // Start at 0
-            size_of::<u16>() as Address,
-            size_of::<Rank>() as Address,
-            size_of::<u16>()
-                as Address + size_of::<Rank>() as Address, // Offset struct
-        ];
-
-        let types = [
-            Count::equivalent_datatype(),
-            u16::equivalent_datatype(),
-            Rank::equivalent_datatype(),
-            ts_equivalent.as_ref()
-        ];
-
-        UserDatatype::structured(&counts, &displacements, &types)
+impl OpId {
+    /// Id of the op invoker started at ts on queue, the invoker's entry goes up by one per op
+    /// so its ids never repeat
+    pub(crate) fn of(queue: QueueId, invoker: Rank, ts: &VectorClock) -> Self {
+        Self { queue, rank: invoker, seq: ts.0[invoker as usize] }
     }
 }
 
-impl std::fmt::Debug for DeqReq {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{{ message: {}, rank: {}, ts: {:?} }}",
-            req_encoding_to_string(self.message), self.rank, self.timestamp
-        )
-    }
+/// What handling a message came to, kept apart from MessageKind so nothing that only means
+/// something inside a process has a wire code
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Handled {
+    #[default]
+    Nothing, // nothing more comes of it here
+    Next(MessageKind), // the message it calls for: ENQ_REQ/DEQ_REQ for an invoke, ENQ_ACK, SAFE or UNSAFE for a request
+    EnqAcked, // an ack was handled, the enqueue might be done. In a world of one the invoke says it
+    Duplicate, // seen before and ignored
 }
 
-#[derive(Copy, Clone)]
-pub struct SafeUnsafeAck {
-    pub message: u16,
-    pub rank: Rank,
-    pub timestamp: VectorClock
+impl Handled {
+    /// The message handling called for, or kind if it didnt call for one
+    pub(crate) fn next_or(self, kind: MessageKind) -> MessageKind {
+        match self {
+            Handled::Next(next) => next,
+            _ => kind,
+        }
+    }
 }
 
-unsafe impl Equivalence for SafeUnsafeAck {
-    type Out = UserDatatype;
-
-    fn equivalent_datatype() -> Self::Out {
-        let ts_equivalent = VectorClock::equivalent_datatype();
-
-        let counts = [
-            1 as Count, // One UnsafeAck
-            1 as Count, // One u16
-            1 as Count, // One Rank
-            1 as Count // One VectorClock
-        ];
+#[derive(Debug, Default, Clone)]
+pub struct OpNextAction<T: Payload> {
+    pub message: Handled,
+    pub value: Option<T>,
+    pub op: OpId,
+    pub ts: VectorClock,
+}
 
-        let displacements = [ // Define memory offsets
-            0 as Address, // Start at 0
-            size_of::<u16>() as Address,
-            size_of::<Rank>() as Address,
-            size_of::<u16>()
-                as Address + size_of::<Rank>() as Address, // Offset struct
-        ];
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueOpReq<T: Payload> {
+    pub message: MessageKind,
+    pub value: T,
+    pub sender: Rank,
+    pub receiver: Rank,
+    pub op: OpId, // operation the message is about, invokes get theirs when handled
+    pub timestamp: VectorClock, // clock of op.queue, each queue keeps its own
+    pub batch: Vec<T>, // every value of a batch enqueue in order, value is a placeholder then
+    pub up_to: u32, // items a batch dequeue takes at most, 0 for a plain dequeue
+    pub info: RankInfo, // what HELLO and DONE say about the sender, zero otherwise
+}
 
-        let types = [
-            Count::equivalent_datatype(),
-            u16::equivalent_datatype(),
-            Rank::equivalent_datatype(),
-            ts_equivalent.as_ref()
-        ];
+/// HELLO and DONE are about the sending rank, not an op, this is what they say
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RankInfo {
+    pub procs: i32, // HELLO: world size the sender expects
+    pub version: i32, // HELLO: its PROTOCOL_VERSION
+    pub payload: u32, // HELLO: its Payload::fingerprint
+    pub ops: i32, // DONE: ops it invoked, on all queues together
+}
 
-        UserDatatype::structured(&counts, &displacements, &types)
+impl<T: Payload> QueueOpReq<T> {
+    /// Queue the message is for, DONE is about the rank and goes with the default queue
+    pub fn queue(&self) -> QueueId {
+        self.op.queue
     }
-}
 
-impl std::fmt::Debug for SafeUnsafeAck {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.message {
-            3 => {
-                write!(
-                    f,
-                    "{{ message: {}, rank: {}, ts: {:?} }}",
-                    req_encoding_to_string(self.message), self.rank, self.timestamp
-                )
-            },
-            4 => {
-                write!(
-                    f,
-                    "{{ message: {}, rank: {} }}",
-                    req_encoding_to_string(self.message), self.rank
-                )
-            },
-            _ => { // should never get here
-                write!(
-                    f,
-                    ""
-                )
-            }
+    /// Splits off the fixed size fields, the timestamp and payload are sent after the header
+    pub(crate) fn header(&self) -> QueueOpHeader {
+        QueueOpHeader {
+            message: self.message.into(),
+            sender: self.sender,
+            receiver: self.receiver,
+            op_queue: self.op.queue,
+            op_rank: self.op.rank,
+            op_seq: self.op.seq,
+            batch: self.batch.len() as u32,
+            up_to: self.up_to,
+            procs: self.info.procs,
+            version: self.info.version,
+            payload: self.info.payload,
+            ops: self.info.ops,
         }
     }
+
+    /// Puts a received message back together, fails if the header has a code we dont know
+    pub(crate) fn from_parts(header: QueueOpHeader, timestamp: VectorClock, value: T, batch: Vec<T>) -> Result<Self, ProtocolError> {
+        Ok(QueueOpReq {
+            message: MessageKind::try_from(header.message)?,
+            value,
+            sender: header.sender,
+            receiver: header.receiver,
+            op: OpId { queue: header.op_queue, rank: header.op_rank, seq: header.op_seq },
+            timestamp,
+            batch,
+            up_to: header.up_to,
+            info: RankInfo { procs: header.procs, version: header.version, payload: header.payload, ops: header.ops },
+        })
+    }
 }
 
-#[derive(Clone, Copy, Debug, Default)]
-pub struct QueueOpReq {
+/// Fixed size part of a QueueOpReq as it goes over MPI. The datatype is derived from the
+/// field offsets, repr(C) keeps those fixed
+#[derive(Equivalence, Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct QueueOpHeader {
     pub message: u16,
-    pub value: u16,
     pub sender: Rank,
     pub receiver: Rank,
-    pub timestamp: VectorClock,
+    pub op_queue: u32,
+    pub op_rank: Rank,
+    pub op_seq: i32,
+    pub batch: u32, // values of a batch enqueue, their lengths and elements follow the payload
+    pub up_to: u32,
+    pub procs: i32, // the RankInfo fields
+    pub version: i32,
+    pub payload: u32,
+    pub ops: i32,
 }
 
-unsafe impl Equivalence for QueueOpReq {
-    type Out = UserDatatype;
-
-    fn equivalent_datatype() -> Self::Out {
-        let ts_equivalent = VectorClock::equivalent_datatype(); //Store to use ref
-
-        let counts = [
-            1 as Count, // One QueueOpReq
-            1 as Count, // One u16 for message
-            1 as Count, // One u16 for value
-            1 as Count, // One Rank
-            1 as Count, // One Rank
-            1 as Count, // One VectorClock
-        ];
+#[cfg(test)]
+mod tests {
+    use mpi::traits::*;
+    use super::*;
+    use crate::util::transport::{ MpiTransport, Transport };
+
+    fn sample_ops<T: Payload>(rank: Rank, size: usize, value: T) -> Vec<QueueOpReq<T>> {
+        let receiver = (rank + 1) % size as Rank;
+        let ts = VectorClock((0..size as i32).map(|i| 10 * i + rank + 1).collect());
+        let op = OpId { queue: queue_id("jobs"), rank, seq: 7 };
+        let batch = vec![value.clone(), T::default(), value.clone()];
+        let none = RankInfo::default();
+        let hello = RankInfo { procs: size as i32, version: 4, payload: 0xF00D, ops: 0 };
+        let done = RankInfo { ops: 12, ..none };
+        [
+            (MessageKind::EnqReq, value, Vec::new(), 0, none),
+            (MessageKind::EnqReq, T::default(), batch, 0, none),
+            (MessageKind::EnqAck, T::default(), Vec::new(), 0, none),
+            (MessageKind::DeqReq, T::default(), Vec::new(), 0, none),
+            (MessageKind::DeqReq, T::default(), Vec::new(), 5, none),
+            (MessageKind::Safe, T::default(), Vec::new(), 0, none),
+            (MessageKind::Unsafe, T::default(), Vec::new(), 5, none),
+            (MessageKind::Done, T::default(), Vec::new(), 0, done),
+            (MessageKind::Hello, T::default(), Vec::new(), 0, hello),
+        ].into_iter()
+            .map(|(message, value, batch, up_to, info)| {
+                QueueOpReq { message, value, sender: rank, receiver, op, timestamp: ts.clone(), batch, up_to, info }
+            })
+            .collect()
+    }
 
-        let displacements = [
-            // Start at 0
-            0 as Address,
-            // Offset for Rank receiver
-            size_of::<Rank>() as Address,
-            // Offset for Rank sender
-            size_of::<Rank>() as Address * 2,
-            // Offset for u16 value
-            size_of::<u16>() as Address * 3,
-            // Offset for u16 message
-            size_of::<u16>() as Address * 4,
-            // Offset for VectorClock timestamp
-            size_of::<Rank>() as Address * 2 + size_of::<u16>() as Address * 2,
-        ];
+    #[test]
+    fn header_keeps_every_field() {
+        for op in sample_ops(1, 3, 42u16) {
+            let back = QueueOpReq::from_parts(op.header(), op.timestamp.clone(), op.value, op.batch.clone()).unwrap();
+            assert_eq!(back, op);
+        }
+        let mut header = sample_ops(0, 2, 1u16)[0].header();
+        header.message = 99;
+        assert_eq!(QueueOpReq::from_parts(header, VectorClock::new(2), 1u16, Vec::new()), Err(ProtocolError::UnknownCode(99)));
+    }
 
-        let types = [
-            Count::equivalent_datatype(),
-            u16::equivalent_datatype(),
-            u16::equivalent_datatype(),
-            Rank::equivalent_datatype(),
-            Rank::equivalent_datatype(),
-            ts_equivalent.as_ref(), // use temporary reference for lifetime requirements
-        ];
+    /// Every rank sends to the next one and checks what the previous one sent. Works on its own
+    /// as a world of one, run the test binary under mpirun to go across ranks
+    #[test]
+    fn messages_round_trip_over_mpi() {
+        let universe = mpi::initialize().unwrap();
+        let world = universe.world();
+        let (rank, size) = (world.rank(), world.size() as usize);
+        let previous = (rank + size as Rank - 1) % size as Rank;
+        let mut transport = MpiTransport::<u16>::new(&universe);
+
+        for op in sample_ops(rank, size, 0xBEEFu16) {
+            transport.send(&op).unwrap();
+        }
+        for expected in sample_ops(previous, size, 0xBEEFu16) {
+            assert_eq!(transport.receive(previous).unwrap(), expected);
+        }
 
-        UserDatatype::structured(&counts, &displacements, &types)
+        let mut transport = MpiTransport::<String>::new(&universe); // same world, a transport carries one payload type
+        let text = format!("from rank {} ✓", rank);
+        for op in sample_ops(rank, size, text) {
+            transport.send(&op).unwrap();
+        }
+        for expected in sample_ops(previous, size, format!("from rank {} ✓", previous)) {
+            assert_eq!(transport.receive(previous).unwrap(), expected);
+        }
+        world.barrier();
     }
 }
-
-#[derive(Clone, Copy, Default)]
-pub struct OpRequest {
-    message: u16,
-    value: Option<u16>,
-    sender: Rank,
-    receiver: Rank,
-}
\ No newline at end of file
@@ -2,7 +2,10 @@ pub mod update_ts;
 pub mod confirmation_list;
 pub(crate) mod process;
 pub(crate) mod compare_ts;
-pub(crate) mod numeric_encodings;
 pub(crate) mod message_structs;
-pub(crate) mod constants;
-pub(crate) mod execution_linearizer;
\ No newline at end of file
+pub(crate) mod payload;
+pub(crate) mod transport;
+pub(crate) mod history;
+pub(crate) mod trace;
+pub(crate) mod protocol;
+pub(crate) mod error;
\ No newline at end of file
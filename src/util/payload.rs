@@ -0,0 +1,73 @@
+use std::fmt::Debug;
+use mpi::datatype::Equivalence;
+use crate::util::message_structs::fnv1a;
+
+/// Anything that can be stored in the queue. The payload is sent over MPI as a run of `Elem`s
+/// after the message header, so the MPI datatype comes from `Elem` and the length can vary
+pub trait Payload: Clone + Default + Debug {
+    /// Element type the payload is sent as, its datatype is what MPI sees on the wire
+    type Elem: Equivalence + Copy + Default;
+
+    fn to_elems(&self) -> Vec<Self::Elem>;
+
+    fn from_elems(elems: &[Self::Elem]) -> Self;
+
+    /// Names the payload type in the startup handshake, ranks that disagree on it would read
+    /// each others items as garbage. Hashes the type name, override it when ranks are built
+    /// from different code that should still talk
+    fn fingerprint() -> u32 {
+        fnv1a(std::any::type_name::<Self>().as_bytes())
+    }
+}
+
+/// Implements `Payload` for a fixed size type that is its own MPI datatype, ie one value per
+/// message. Works for anything with `#[derive(Equivalence, Clone, Copy, Default, Debug)]`
+#[macro_export]
+macro_rules! fixed_payload {
+    ($($t:ty),* $(,)?) => {
+        $(
+            impl $crate::Payload for $t {
+                type Elem = $t;
+
+                fn to_elems(&self) -> Vec<Self::Elem> {
+                    vec![*self]
+                }
+
+                fn from_elems(elems: &[Self::Elem]) -> Self {
+                    elems.first().copied().unwrap_or_default()
+                }
+            }
+        )*
+    };
+}
+
+fixed_payload!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+/// Variable length payloads, `Vec<u8>` covers byte blobs
+impl<E> Payload for Vec<E>
+where
+    E: Equivalence + Copy + Default + Debug,
+{
+    type Elem = E;
+
+    fn to_elems(&self) -> Vec<Self::Elem> {
+        self.clone()
+    }
+
+    fn from_elems(elems: &[Self::Elem]) -> Self {
+        elems.to_vec()
+    }
+}
+
+/// Strings go over the wire as their utf8 bytes
+impl Payload for String {
+    type Elem = u8;
+
+    fn to_elems(&self) -> Vec<Self::Elem> {
+        self.as_bytes().to_vec()
+    }
+
+    fn from_elems(elems: &[Self::Elem]) -> Self {
+        String::from_utf8_lossy(elems).into_owned()
+    }
+}
\ No newline at end of file
@@ -1,82 +1,86 @@
 use std::cmp::Ordering;
-use std::collections::VecDeque;
-use std::{fmt, io};
-use std::time::Duration;
-use mpi::environment::Universe;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::thread;
+use std::time::{ Duration, Instant };
 use mpi::Rank;
-use mpi::topology::SimpleCommunicator;
-use mpi::traits::*;
-use mpi::request::WaitGuard;
-use chrono::Local;
 use crate::util::compare_ts::{ compare_ts, compare_ts_ord, ComparisonResult, contains_all_zeros };
-use crate::util::message_structs::{ DeqReq, EnqReq, SafeUnsafeAck, VectorClock };
-use crate::util::message_structs::{ QueueOpReq, OpNextAction };
+use crate::util::message_structs::{ queue_id, OpId, QueueId, VectorClock, DEFAULT_QUEUE };
+use crate::util::message_structs::{ Handled, QueueOpReq, OpNextAction };
 use crate::util::confirmation_list::{ ConfirmationList,propagate_earlier_responses };
-use crate::util::confirmation_list::{ update_unsafes, print_confirmation_lists };
-use crate::util::constants::{ NUM_PROCS, ENQ_REQ, DEQ_REQ, ENQ_ACK };
-use crate::util::constants::{ UNSAFE, SAFE, ENQ_INVOKE, DEQ_INVOKE };
+use crate::util::confirmation_list::update_unsafes;
+use crate::util::history::{ QueueCall, QueueResult };
+use crate::util::error::QueueError;
 use crate::util::update_ts::update_ts;
+use crate::util::payload::Payload;
+use crate::util::protocol::{ Message, MessageKind, ProtocolError, PROTOCOL_VERSION };
+use crate::util::transport::Transport;
+use crate::util::trace::TraceRecorder;
 
 
-const PLACEHOLDER: u16 = 0xFFFC;
-
-#[derive(Debug)]
-struct OutOfRangeError;
-
-pub enum HandleDequeue {
-    Success((Rank, u16)),
-    NoResult,
+/// What to do with a message that was already handled once, eg resent by a flaky network
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicatePolicy {
+    Tolerate, // drop it and carry on
+    Report, // drop it, warn and keep it in Process::duplicates
 }
 
-impl fmt::Display for OutOfRangeError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Process out of range world size!")
+/// Ops that wont get another message here, per queue and invoker. An invoker's seqs on a queue
+/// go 1, 2, 3.. and every one of them retires eventually, so a counter covers all but the few
+/// that retired out of order
+#[derive(Clone, Default)]
+struct Retired(HashMap<(QueueId, Rank), (i32, HashSet<i32>)>);
+
+impl Retired {
+    fn insert(&mut self, op: OpId) {
+        let (upto, above) = self.0.entry((op.queue, op.rank)).or_default();
+        above.insert(op.seq);
+        while above.remove(&(*upto + 1)) {
+            *upto += 1;
+        }
+    }
+
+    fn contains(&self, op: &OpId) -> bool {
+        self.0.get(&(op.queue, op.rank))
+            .is_some_and(|(upto, above)| op.seq <= *upto || above.contains(&op.seq))
     }
 }
-impl std::error::Error for OutOfRangeError {}
 
+/// One logical queue as this rank sees it. Queues only share the ranks and the transport, each
+/// has its own clock so an op on one never waits for ops on another
 #[derive(Clone)]
-pub struct Process<'universe> {
-    pub(crate) index: Rank, // stores process index
-    pub(crate) vector_clock: VectorClock, // stores process vector clock
+pub(crate) struct QueueState<T: Payload> {
+    pub(crate) name: Option<String>, // None until opened here, another rank can use it first
+    pub(crate) vector_clock: VectorClock, // stores the queue's vector clock
     pub(crate) lists: Vec<ConfirmationList>, // stores confirmation lists
-    pub(crate) enq_count: u16, // stores number of enqueues
-    pub(crate) local_queue:VecDeque<(Rank, u16, VectorClock)>, // stores a local copy of the queue sorted by ts
-    formatted_strings:Vec<String>, // for debugging
-    message_buffer: [OpNextAction; NUM_PROCS], // buffer to hold up to NUM_PROCS incoming messages
-    universe: &'universe Universe,
+    pub(crate) local_queue:VecDeque<(OpId, T, VectorClock)>, // stores a local copy of the queue sorted by ts, with the enqueue that put each item there
+    pub(crate) arrived: usize, // items that ever reached local_queue, takes wait for it to change
 }
 
-impl<'universe> Process<'universe> {
-    pub(crate) fn initialize(universe: &'universe Universe) -> Self {
-        let enq_count = 0u16; // initialize enq_count to 0
-
+impl<T: Payload> QueueState<T> {
+    fn new(num_procs: usize) -> Self {
         Self {
-            index: universe.world().rank(), // get process ID from MPI world
-            vector_clock: VectorClock([0; NUM_PROCS]),
+            name: None,
+            vector_clock: VectorClock::new(num_procs),
             lists: Vec::new(), // holds confirmation lists
-            enq_count, // counts num enqueues
             local_queue: VecDeque::new(), // initialize empty local queue
-            formatted_strings: Vec::new(),
-            message_buffer: Default::default(),
-            universe
+            arrived: 0,
         }
     }
 
-    pub(crate) fn enqueue_local(&mut self, item: (Rank, u16, VectorClock)) {
-        let ts_to_insert = &item.2.0; // 3rd element of the tuple, 1st field of VC
+    /// Puts the values of enqueue op in the local queue by ts, a batch stays together and in order
+    pub(crate) fn enqueue_local(&mut self, op: OpId, values: Vec<T>, ts: VectorClock) {
+        self.arrived += values.len();
+        let ts_to_insert = &ts.0;
 
         // Use binary_search_by with the custom comparator
-        match self.local_queue.binary_search_by(|&(_, _, ref ts)| compare_ts_ord(&ts.0, ts_to_insert)) {
-            Ok(insert_position) => {
-                // Insert at the correct position
-                self.local_queue.insert(insert_position, item);
-            }
-            Err(insert_position) => {
-                // If binary_search returns Err, insert at the calculated position
-                self.local_queue.insert(insert_position, item);
-            }
-        }
+        let insert_position = match self.local_queue.binary_search_by(|&(_, _, ref ts)| compare_ts_ord(&ts.0, ts_to_insert)) {
+            Ok(insert_position) => insert_position, // Insert at the correct position
+            Err(insert_position) => insert_position, // If binary_search returns Err, insert at the calculated position
+        };
+        let later = self.local_queue.split_off(insert_position);
+        self.local_queue.extend(values.into_iter().map(|value| (op, value, ts.clone())));
+        self.local_queue.extend(later);
     }
 
     pub(crate) fn add_confirmation_list(&mut self, confirmation_list: ConfirmationList) {
@@ -95,345 +99,864 @@ impl<'universe> Process<'universe> {
         }
     }
 
-    pub(crate) fn remove_confirmation_list(&mut self, ts: &VectorClock) {
-        for (index, confirmation_list) in self.lists.iter().enumerate() {
-            if compare_ts_ord(&ts.0, &confirmation_list.ts) == Ordering::Equal {
-                self.lists.remove(index);
-                break; // Shouldnt have duplicate ts
+    pub(crate) fn remove_confirmation_list(&mut self, op: OpId) {
+        if let Some(index) = self.lists.iter().position(|confirmation_list| confirmation_list.op == op) {
+            self.lists.remove(index);
+        }
+    }
+
+    /// Takes the earliest dequeue whose confirmation list has heard from every process by
+    /// removing its item from the local queue, or the items of a batch dequeue. Returns the
+    /// dequeue, its ts and what it got
+    fn take_dequeue(&mut self) -> Option<(OpId, VectorClock, QueueResult<T>)> {
+        for (i, confirmation_list) in self.lists.iter_mut().enumerate() {
+            if !confirmation_list.response_list.contains(&0) && !confirmation_list.handled {
+                let mut pos: usize = 0;
+                for response in confirmation_list.response_list.iter() {
+                    if *response == 2 {
+                        pos += 1;
+                    }
+                }
+                confirmation_list.handled = true;
+                let id = confirmation_list.op;
+                let deq_ts = VectorClock(confirmation_list.ts.clone());
+                let result = match confirmation_list.up_to as usize {
+                    0 => QueueResult::Dequeued(self.local_queue.remove(pos).map(|(_, val,_)| val)),
+                    up_to => {
+                        let start = pos.min(self.local_queue.len()); // empty like a plain dequeue's ⊥ past the end
+                        let end = (pos + up_to).min(self.local_queue.len());
+                        QueueResult::DequeuedMany(self.local_queue.drain(start..end).map(|(_, val, _)| val).collect())
+                    }
+                };
+                update_unsafes(&mut self.lists, i+1);
+                return Some((id, deq_ts, result));
+            }
+        }
+
+        None
+    }
+}
+
+#[derive(Clone)]
+pub struct Process<T: Payload, C: Transport<T>> {
+    pub(crate) index: Rank, // stores process index
+    pub(crate) num_procs: usize, // stores world size, taken from the transport at startup
+    pub(crate) queues: BTreeMap<QueueId, QueueState<T>>, // every queue we opened or heard of, the default one is always there
+    invoked: i32, // ops invoked here on any queue, DONE tells the others
+    enq_acks: HashMap<OpId, (HashSet<Rank>, Vec<T>)>, // who acked each of our enqueues in flight, we count ourselves, and its values
+    pending: HashMap<OpId, VectorClock>, // our own ops started with start that are still in flight, with their ts
+    pub(crate) completed: VecDeque<(OpId, QueueResult<T>)>, // our own finished ops
+    cancelled: HashSet<OpId>, // ops still in flight whose result nobody wants anymore
+    orphaned: Vec<T>, // items cancelled dequeues took off the queue, until someone drains them
+    loopback: VecDeque<QueueOpReq<T>>, // messages to ourselves, they dont go through the transport
+    done_from: HashMap<Rank, i32>, // ranks that sent DONE, with how many ops they invoked
+    pub(crate) transport: C, // how messages reach the other ranks
+    pub(crate) trace: Option<TraceRecorder>, // structured event log, off unless someone asks for it
+    pub(crate) verbose: bool, // print what we handle, too much when exploring thousands of runs
+    seen: HashMap<OpId, HashSet<(MessageKind, Rank)>>, // (message, sender) handled so far of ops that still get messages
+    retired: Retired, // ops that got all their messages, anything else for them is a duplicate
+    greeted: HashSet<Rank>, // ranks whose HELLO we had
+    requests_from: HashMap<Rank, i32>, // ENQ_REQs and DEQ_REQs handled per sender, finished compares them to DONE
+    pub(crate) on_duplicate: DuplicatePolicy,
+    pub(crate) duplicates: Vec<QueueOpReq<T>>, // duplicates seen under DuplicatePolicy::Report
+}
+
+impl<T: Payload, C: Transport<T>> Process<T, C> {
+    pub(crate) fn initialize(transport: C) -> Self {
+        let num_procs = transport.size(); // size clocks and lists from the world
+
+        let mut default = QueueState::new(num_procs);
+        default.name = Some(String::new());
+        Self {
+            index: transport.rank(), // get process ID from the transport
+            num_procs,
+            queues: BTreeMap::from([(DEFAULT_QUEUE, default)]),
+            invoked: 0,
+            enq_acks: HashMap::new(),
+            pending: HashMap::new(),
+            completed: VecDeque::new(),
+            cancelled: HashSet::new(),
+            orphaned: Vec::new(),
+            loopback: VecDeque::new(),
+            done_from: HashMap::new(),
+            transport,
+            trace: None,
+            verbose: true,
+            seen: HashMap::new(),
+            retired: Retired::default(),
+            greeted: HashSet::new(),
+            requests_from: HashMap::new(),
+            on_duplicate: DuplicatePolicy::Tolerate,
+            duplicates: Vec::new(),
+        }
+    }
+
+    /// Opens the queue called name, every rank that uses it has to call it by the same name.
+    /// Fails if another name we opened has the same id
+    pub(crate) fn open(&mut self, name: &str) -> Result<QueueId, QueueError> {
+        let id = queue_id(name);
+        let state = self.queue_mut(id);
+        match &state.name {
+            Some(opened) if opened != name => {
+                return Err(QueueError::QueueIdClash { opened: opened.clone(), name: name.to_string(), id });
+            }
+            Some(_) => {}
+            None => state.name = Some(name.to_string()),
+        }
+        Ok(id)
+    }
+
+    /// State of queue, made on first use since other ranks can use a queue before we open it
+    pub(crate) fn queue_mut(&mut self, queue: QueueId) -> &mut QueueState<T> {
+        let num_procs = self.num_procs;
+        self.queues.entry(queue).or_insert_with(|| QueueState::new(num_procs))
+    }
+
+    /// Clock of queue, all zeros if nothing happened on it yet
+    pub(crate) fn clock(&self, queue: QueueId) -> VectorClock {
+        self.queues.get(&queue)
+            .map_or_else(|| VectorClock::new(self.num_procs), |state| state.vector_clock.clone())
+    }
+
+    pub(crate) fn trace_send(&mut self, op: &QueueOpReq<T>) {
+        if self.trace.is_some() {
+            let clock = self.clock(op.queue());
+            self.trace.as_mut().unwrap().send(op, &clock);
+        }
+    }
+
+    pub(crate) fn trace_receive(&mut self, op: &QueueOpReq<T>) {
+        if self.trace.is_some() {
+            let clock = self.clock(op.queue());
+            self.trace.as_mut().unwrap().receive(op, &clock);
+        }
+    }
+
+    /// Finishes the earliest dequeue on queue whose confirmation list has heard from every
+    /// process. Returns the dequeue and what it got
+    pub(crate) fn complete_dequeue(&mut self, queue: QueueId) -> Option<(OpId, QueueResult<T>)> {
+        let state = self.queues.get_mut(&queue)?;
+        let (id, deq_ts, result) = state.take_dequeue()?;
+        if self.verbose {
+            match &result {
+                QueueResult::Dequeued(Some(val)) => println!("Process{} got {:?}", self.index, val),
+                QueueResult::DequeuedMany(vals) => println!("Process{} got {:?}", self.index, vals),
+                _ => println!("Process{} got ⊥", self.index),
+            }
+        }
+        match (&mut self.trace, &result) {
+            (Some(trace), QueueResult::Dequeued(deq_val)) => {
+                trace.response(MessageKind::DeqInvoke, id, &deq_ts, deq_val.as_ref(), &state.vector_clock);
+            }
+            (Some(trace), QueueResult::DequeuedMany(deq_vals)) => {
+                trace.response_batch(MessageKind::DeqInvoke, id, &deq_ts, deq_vals, &state.vector_clock);
+            }
+            _ => {}
+        }
+        self.complete(id, result.clone());
+        Some((id, result))
+    }
+
+    /// Hands the result of id to whoever waits on it, if it is ours and still wanted
+    fn complete(&mut self, id: OpId, result: QueueResult<T>) {
+        if self.pending.remove(&id).is_some() {
+            if self.cancelled.remove(&id) {
+                self.orphan(result);
+            } else {
+                self.completed.push_back((id, result));
             }
         }
     }
 
-    pub(crate) fn print_execution(&mut self) {
-        let combined_string = self.formatted_strings.join("\n");
-        let execution_output = format!("========== Execution for process {} ===========\n", self.index) + &*combined_string + "================================\n";
-        println!("{}", execution_output);
+    /// Keeps what a dequeue nobody waits for got, it is off the queue on every rank already
+    pub(crate) fn orphan(&mut self, result: QueueResult<T>) {
+        match result {
+            QueueResult::Dequeued(Some(value)) => self.orphaned.push(value),
+            QueueResult::DequeuedMany(values) => self.orphaned.extend(values),
+            _ => {}
+        }
+    }
+
+    /// Remembers message and tells whether it was seen before. Only messages between ranks count,
+    /// UNSAFE is kept as SAFE since a rank answers a dequeue once either way. An op is
+    /// forgotten once it got everything it can get here: an enqueue its ENQ_REQ or, if it is
+    /// ours, every ack, a dequeue its DEQ_REQ and a SAFE/UNSAFE from every rank
+    fn is_duplicate(&mut self, sender: Rank, message: &Message<T>) -> bool {
+        let (kind, op) = match message {
+            Message::EnqReq { op, .. } | Message::EnqAck { op, .. } | Message::DeqReq { op, .. } => {
+                (message.kind(), *op)
+            }
+            Message::Safe { op, .. } | Message::Unsafe { op, .. } => (MessageKind::Safe, *op),
+            Message::Hello { .. } => return !self.greeted.insert(sender), // one per rank, the handshake keeps the first
+            _ => return false,
+        };
+        if self.retired.contains(&op) {
+            return true;
+        }
+        let got = self.seen.entry(op).or_default();
+        if !got.insert((kind, sender)) {
+            return true;
+        }
+
+        let all_in = match kind {
+            MessageKind::EnqReq => true,
+            MessageKind::EnqAck => got.len() == self.num_procs - 1,
+            _ => got.len() == self.num_procs + 1,
+        };
+        if all_in {
+            self.seen.remove(&op);
+            self.retired.insert(op);
+        }
+        if kind == MessageKind::EnqReq || kind == MessageKind::DeqReq {
+            *self.requests_from.entry(sender).or_default() += 1;
+        }
+        false
     }
 
-    pub(crate) fn handle_queue_op(&mut self, op: QueueOpReq) -> OpNextAction{
-        let mut res: OpNextAction = OpNextAction::default();
-        match op.message {
-            ENQ_INVOKE => {
-                self.enq_count = 0;
-                self.vector_clock.0[self.index as usize] += 1;
-                self.enq_count += 1;
-                println!("{} enquing at ts {:?}", self.index, self.vector_clock.0);
-                res = OpNextAction{
-                    message: ENQ_REQ, value: Option::from(op.value),
-                    invoker: self.index, ts: self.vector_clock
+    /// Handles one message, errors if it doesnt decode to something this process understands
+    pub(crate) fn handle_queue_op(&mut self, op: QueueOpReq<T>) -> Result<OpNextAction<T>, ProtocolError> {
+        let sender = op.sender;
+        let message = Message::decode(&op, self.num_procs)?;
+        if self.is_duplicate(sender, &message) {
+            if self.on_duplicate == DuplicatePolicy::Report {
+                eprintln!("Process {} got a duplicate {} from {} at ts {:?}",
+                          self.index, op.message, op.sender, op.timestamp);
+                self.duplicates.push(op.clone());
+            }
+            return Ok(OpNextAction{
+                message: Handled::Duplicate, value: None,
+                op: op.op, ts: op.timestamp
+            });
+        }
+
+        let queue = op.queue();
+        let num_procs = self.num_procs;
+        let state = self.queues.entry(queue).or_insert_with(|| QueueState::new(num_procs));
+        let res = match message {
+            Message::EnqInvoke { values, .. } => {
+                state.vector_clock.0[self.index as usize] += 1;
+                self.invoked += 1;
+                let id = OpId::of(queue, self.index, &state.vector_clock);
+                let ts = state.vector_clock.clone();
+                if self.verbose {
+                    println!("{} enquing at ts {:?}", self.index, state.vector_clock.0);
+                }
+                match (&mut self.trace, &values[..]) {
+                    (Some(trace), [value]) => trace.invoke(MessageKind::EnqInvoke, id, Some(value), &state.vector_clock),
+                    (Some(trace), values) => trace.invoke_batch(MessageKind::EnqInvoke, id, values, 0, &state.vector_clock),
+                    _ => {}
+                }
+                let value = values.first().cloned();
+                let message = if self.num_procs == 1 {
+                    // nobody else has to ack, it is done once it is in our queue
+                    if let Some(trace) = &mut self.trace {
+                        trace.enqueued(id, &ts, &values, &state.vector_clock);
+                    }
+                    state.enqueue_local(id, values, ts.clone());
+                    self.retired.insert(id);
+                    Handled::EnqAcked
+                } else {
+                    self.enq_acks.insert(id, (HashSet::from([self.index]), values));
+                    Handled::Next(MessageKind::EnqReq)
+                };
+                OpNextAction{
+                    message, value,
+                    op: id, ts
                 }
             }
-            ENQ_REQ => {
-                update_ts(&mut self.vector_clock.0, &op.timestamp.0);
-                self.enqueue_local((op.sender, op.value, op.timestamp));
+            Message::EnqReq { op: id, values, ts } => {
+                update_ts(&mut state.vector_clock.0, &ts.0);
+                let value = values.first().cloned();
+                state.enqueue_local(id, values, ts.clone());
 
-                for confirmationList in &mut self.lists {
-                    match compare_ts(&confirmationList.ts, &self.vector_clock.0) {
+                for confirmation_list in &mut state.lists {
+                    match compare_ts(&confirmation_list.ts, &state.vector_clock.0) {
                         ComparisonResult::Less | ComparisonResult::StrictlyLess // accept less or strictly less
-                        => confirmationList.response_list[self.index as usize] = 1,
+                        => confirmation_list.response_list[self.index as usize] = 1,
                         _ => {}
                     }
                 }
-                res = OpNextAction{
-                    message: ENQ_ACK, value: Option::from(op.value),
-                    invoker: op.sender, ts: op.timestamp
+                OpNextAction{
+                    message: Handled::Next(MessageKind::EnqAck), value,
+                    op: id, ts
                 }
             }
-            ENQ_ACK => {
-                println!("{} got enq ack", self.index);
-                self.enq_count += 1;
-                if self.enq_count == NUM_PROCS as u16 {
-                    self.enqueue_local((self.index, op.value, op.timestamp));
-                    println!("Process {} finished enqueue", self.index);
+            Message::EnqAck { op: id, ts } => {
+                if self.verbose {
+                    println!("{} got enq ack", self.index);
+                }
+                let acked = match self.enq_acks.get_mut(&id) {
+                    Some((acks, _)) => {
+                        acks.insert(sender);
+                        acks.len() == self.num_procs
+                    }
+                    None => false, // not an enqueue of ours thats in flight
+                };
+                let mut value = None;
+                if acked {
+                    let (_, values) = self.enq_acks.remove(&id).unwrap_or_default();
+                    value = values.first().cloned();
+                    if let Some(trace) = &mut self.trace {
+                        trace.enqueued(id, &ts, &values, &state.vector_clock);
+                    }
+                    state.enqueue_local(id, values, ts.clone());
+                    if self.verbose {
+                        println!("Process {} finished enqueue", self.index);
+                    }
+                    self.complete(id, QueueResult::Enqueued);
                 }
-                res = OpNextAction{
-                    message: 9, value: Option::from(op.value),
-                    invoker: self.index, ts: op.timestamp
+                OpNextAction{
+                    message: Handled::EnqAcked, value,
+                    op: id, ts
                 }
             }
-            DEQ_INVOKE => {
-                self.vector_clock.0[self.index as usize] += 1;
-                println!("Process {} DEQ at ts {:?}", self.index, self.vector_clock);
-                res = OpNextAction{
-                    message: DEQ_REQ, value: Option::from(op.value),
-                    invoker: self.index, ts: self.vector_clock
+            Message::DeqInvoke { up_to, .. } => {
+                state.vector_clock.0[self.index as usize] += 1;
+                self.invoked += 1;
+                let id = OpId::of(queue, self.index, &state.vector_clock);
+                if self.verbose {
+                    println!("Process {} DEQ at ts {:?}", self.index, state.vector_clock);
                 }
-            }
-            DEQ_REQ => {
-                println!("Process {} recv deq_req with ts: {:?} self: {:?}",
-                         self.index, op.timestamp.0, self.vector_clock.0);
-                update_ts(&mut self.vector_clock.0, &op.timestamp.0);
-                match compare_ts_ord(&op.timestamp.0, &self.vector_clock.0) {
-                    Ordering::Less  if !contains_all_zeros(&op.timestamp.0)=> {
-                        // unsafe
-                        res = OpNextAction{
-                            message: UNSAFE, value: Option::from(op.value),
-                            invoker: op.sender, ts: op.timestamp
-                        }
-                    }
-                    _ => {  // safe
-                        res = OpNextAction{
-                            message: SAFE, value: Option::from(op.value),
-                            invoker: op.sender, ts: op.timestamp
-                        }
-                    }
+                match &mut self.trace {
+                    Some(trace) if up_to > 0 => trace.invoke_batch::<T>(MessageKind::DeqInvoke, id, &[], up_to, &state.vector_clock),
+                    Some(trace) => trace.invoke::<T>(MessageKind::DeqInvoke, id, None, &state.vector_clock),
+                    None => {}
+                }
+                OpNextAction{
+                    message: Handled::Next(MessageKind::DeqReq), value: Some(T::default()),
+                    op: id, ts: state.vector_clock.clone()
                 }
             }
-            SAFE | UNSAFE => {
-                if op.message == UNSAFE {
-                    println!("Process {} recv UNSAFE from {} at ts {:?}",
-                             self.index, op.sender, op.timestamp.0);
-                }else{
-                    println!("Process {} recv SAFE from {} at ts {:?}",
-                             self.index, op.sender, op.timestamp.0);
+            Message::DeqReq { op: id, ts, .. } => {
+                if self.verbose {
+                    println!("Process {} recv deq_req with ts: {:?} self: {:?}",
+                             self.index, ts.0, state.vector_clock.0);
                 }
-
-                let mut contains_req = false;
-                for confirmation_list in &mut self.lists.iter() {
-                    match compare_ts_ord(&confirmation_list.ts, &op.timestamp.0) {
-                        Ordering::Equal => {
-                            contains_req = true;
-                        }
-                        _ => {}
-                    }
+                update_ts(&mut state.vector_clock.0, &ts.0);
+                let message = match compare_ts_ord(&ts.0, &state.vector_clock.0) {
+                    Ordering::Less if !contains_all_zeros(&ts.0) => Handled::Next(MessageKind::Unsafe),
+                    _ => Handled::Next(MessageKind::Safe),
+                };
+                OpNextAction{
+                    message, value: Some(T::default()),
+                    op: id, ts
                 }
-                if !contains_req { // we dont have this ts in our confirmation list
-                    self.add_confirmation_list(ConfirmationList::new(op.timestamp.0))
+            }
+            Message::Safe { op: id, up_to, ts } | Message::Unsafe { op: id, up_to, ts } => {
+                let is_unsafe = op.message == MessageKind::Unsafe;
+                if self.verbose {
+                    println!("Process {} recv {} from {} at ts {:?}",
+                             self.index, op.message, sender, ts.0);
                 }
 
-                for mut confirmation_list in self.lists.iter_mut() {
-                    match compare_ts_ord(&confirmation_list.ts, &op.timestamp.0) {
-                        Ordering::Equal => {
-                            confirmation_list.response_list[op.sender as usize] =
-                                if op.message == UNSAFE {2} else {1};
-                        }
-                        _ => {}
-                    }
+                let contains_req = state.lists.iter()
+                    .any(|confirmation_list| confirmation_list.op == id);
+                if !contains_req { // we dont have this dequeue in our confirmation lists
+                    state.add_confirmation_list(ConfirmationList::new(id, ts.0.clone(), up_to))
                 }
 
-                propagate_earlier_responses(&mut self.lists);
-                let ret_message:u16 = if op.message == UNSAFE {UNSAFE} else {SAFE};
-
-                for (i, confirmation_list) in self.lists.iter_mut().enumerate() {
-                    if !confirmation_list.response_list.contains(&0) && !confirmation_list.handled {
-                        let mut pos: usize = 0;
-                        for response in confirmation_list.response_list.iter() {
-                            if *response == 2 {
-                                pos += 1;
-                            }
-                        }
-                        confirmation_list.handled = true;
-                        let deq_val: Option<_> = self.local_queue
-                            .remove(pos).map(|(_, val,_)| val);
-                        match deq_val {
-                            Some(val) => println!("Process{} got {}", self.index, val),
-                            None => println!("Process{} got ⊥", self.index),
-                        }
-                        update_unsafes(&mut self.lists, i+1);
-                        return OpNextAction{
-                            message: ret_message,
-                            value: deq_val,
-                            invoker: self.index,
-                            ts: self.vector_clock
-                        };
+                for confirmation_list in state.lists.iter_mut() {
+                    if confirmation_list.op == id {
+                        confirmation_list.response_list[sender as usize] =
+                            if is_unsafe {2} else {1};
                     }
+                }
 
+                propagate_earlier_responses(&mut state.lists);
+                let clock = state.vector_clock.clone();
+
+                let mut value = Some(T::default());
+                if let Some((_, result)) = self.complete_dequeue(queue) {
+                    // later lists may have become complete as well, drivers find them in completed
+                    while self.complete_dequeue(queue).is_some() {}
+                    value = match result {
+                        QueueResult::Dequeued(deq_val) => deq_val,
+                        _ => None, // the lockstep drivers that read this dont batch
+                    };
+                }
+                OpNextAction{
+                    message: Handled::Nothing,
+                    value,
+                    op: id,
+                    ts: clock
+                }
+            }
+            Message::Hello { .. } => return Err(ProtocolError::OutOfPlace(MessageKind::Hello)),
+            Message::Done { ops } => {
+                self.done_from.insert(sender, ops);
+                OpNextAction{
+                    message: Handled::Nothing, value: None,
+                    op: op.op, ts: op.timestamp
                 }
+            }
+        };
+        self.prune_confirmation_lists(queue);
+        Ok(res)
+    }
 
-                return OpNextAction{
-                    message: ret_message,
-                    value: Option::from(op.value),
-                    invoker: self.index,
-                    ts: self.vector_clock
-                };
+    /// Drops the lists of dequeues that are handled and wont get another message, a list kept
+    /// after that only makes every later SAFE/UNSAFE scan longer
+    fn prune_confirmation_lists(&mut self, queue: QueueId) {
+        let Some(state) = self.queues.get_mut(&queue) else { return };
+        let done: Vec<OpId> = state.lists.iter()
+            .filter(|confirmation_list| confirmation_list.handled && self.retired.contains(&confirmation_list.op))
+            .map(|confirmation_list| confirmation_list.op)
+            .collect();
+        for op in done {
+            state.remove_confirmation_list(op);
+        }
+    }
 
+    /// Tells every other rank the world size this one expects, its protocol version and payload
+    /// type, then checks theirs. Has to come before anything else goes out, every rank sends
+    /// first so a rank that fails here doesnt leave the others waiting. expected is the world
+    /// size this rank was configured for, if it was
+    pub(crate) fn handshake(&mut self, expected: Option<usize>) -> Result<(), QueueError> {
+        let hello = Message::Hello {
+            procs: expected.unwrap_or(self.num_procs) as i32,
+            version: PROTOCOL_VERSION,
+            payload: T::fingerprint(),
+        };
+        let clock = VectorClock::new(self.num_procs);
+        for i in (0..self.num_procs as Rank).filter(|&i| i != self.index) {
+            self.transport.send(&hello.clone().encode(self.index, i, &clock))?;
+        }
+        if let Some(expected) = expected.filter(|&expected| expected != self.num_procs) {
+            return Err(QueueError::WorldSizeMismatch { expected, actual: self.num_procs });
+        }
+
+        for i in (0..self.num_procs as Rank).filter(|&i| i != self.index) {
+            let op = self.transport.receive(i)?; // a rank's first message is its hello
+            let Message::Hello { procs, version, payload } = Message::decode(&op, self.num_procs)? else {
+                return Err(ProtocolError::OutOfPlace(op.message).into());
+            };
+            self.greeted.insert(i);
+            if version != PROTOCOL_VERSION {
+                return Err(QueueError::VersionMismatch { rank: i, ours: PROTOCOL_VERSION, theirs: version });
+            }
+            if procs as usize != self.num_procs {
+                return Err(QueueError::WorldSizeMismatch { expected: procs as usize, actual: self.num_procs });
             }
-            _ => {
-                res = OpNextAction::default();
+            if payload != T::fingerprint() {
+                return Err(QueueError::PayloadMismatch { rank: i, ours: T::fingerprint(), theirs: payload });
             }
         }
-        res
+        Ok(())
     }
 
-    pub(crate) fn sync_send_receive(&mut self, universe: &Universe, op: QueueOpReq) -> OpNextAction {
-        if (op.receiver == op.sender)  && self.index == op.sender {
-            return self.handle_queue_op(op); // dont need to use MPI to send to self
-        } else if op.receiver == op.sender { // do nothing
-            return OpNextAction::default();
+    /// Starts call on queue from this rank. Returns the id of the operation and the messages
+    /// that have to go out for it, the caller delivers them. Any number of ops can be in flight
+    /// at once, on any queues
+    pub(crate) fn start(&mut self, queue: QueueId, call: &QueueCall<T>) -> Result<(OpId, Vec<QueueOpReq<T>>), QueueError> {
+        match call {
+            // would go out as an enqueue of T::default() or a plain dequeue
+            QueueCall::EnqueueMany(values) if values.is_empty() => return Err(QueueError::EmptyBatch),
+            QueueCall::DequeueUpTo(0) => return Err(QueueError::EmptyBatch),
+            _ => {}
+        }
+        let up_to = match call {
+            QueueCall::DequeueUpTo(up_to) => u32::try_from(*up_to).unwrap_or(u32::MAX),
+            _ => 0,
+        };
+        let invoke = match call {
+            QueueCall::Enqueue(value) => Message::EnqInvoke { queue, values: vec![value.clone()] },
+            QueueCall::EnqueueMany(values) => Message::EnqInvoke { queue, values: values.clone() },
+            QueueCall::Dequeue | QueueCall::DequeueUpTo(_) => Message::DeqInvoke { queue, up_to },
+        };
+        let clock = self.clock(queue);
+        let res = self.handle_queue_op(invoke.encode(self.index, self.index, &clock))
+            .expect("an invoke built here always decodes");
+        let id = res.op;
+        let ts = res.ts;
+        self.pending.insert(id, ts.clone());
+        if res.message == Handled::EnqAcked {
+            self.complete(id, QueueResult::Enqueued); // a world of one, there are no acks to wait for
         }
 
-        let mut recv_op = QueueOpReq::default();
+        let messages = (0..self.num_procs as Rank)
+            .filter_map(|i| match call {
+                QueueCall::Enqueue(_) | QueueCall::EnqueueMany(_) if i == self.index => None, // the invoker already holds its own enqueue
+                QueueCall::Enqueue(value) => Some(Message::EnqReq { op: id, values: vec![value.clone()], ts: ts.clone() }),
+                QueueCall::EnqueueMany(values) => Some(Message::EnqReq { op: id, values: values.clone(), ts: ts.clone() }),
+                QueueCall::Dequeue | QueueCall::DequeueUpTo(_) => Some(Message::DeqReq { op: id, up_to, ts: ts.clone() }),
+            }.map(|message| message.encode(self.index, i, &ts)))
+            .collect();
+        Ok((id, messages))
+    }
 
-        let world = universe.world();
-        mpi::request::scope(|scope| {
-            if self.index == op.sender {
-                let mut sreq = world.process_at_rank(op.receiver)
-                    .immediate_send(scope, &op);
-                loop {
-                    match sreq.test() {
-                        Ok(_) => break,
-                        Err(req) => sreq = req,
-                    }
-                }
-            } else if world.rank() == op.receiver {
-                let rreq = WaitGuard::from(world.process_at_rank(op.sender)
-                    .immediate_receive_into(scope, &mut recv_op));
-                drop(rreq);
-            }
-        });
+    /// Handles a message from another rank (or ourselves) and returns the replies it causes.
+    /// Operations of ours that finish show up in completed. Fails on a message that doesnt
+    /// decode, whoever sent it is broken and the replicas cant be trusted to agree anymore
+    pub(crate) fn react(&mut self, op: QueueOpReq<T>) -> Result<Vec<QueueOpReq<T>>, ProtocolError> {
+        let message = op.message;
+        let sender = op.sender;
+        let id = op.op;
+        let ts = op.timestamp.clone();
+        let up_to = op.up_to;
+        let res = self.handle_queue_op(op)?;
+
+        let replies = match (message, res.message) {
+            (_, Handled::Duplicate) => Vec::new(), // already answered the first copy
+            (MessageKind::EnqReq, _) => vec![(sender, Message::EnqAck { op: id, ts })],
+            (MessageKind::DeqReq, reply) => (0..self.num_procs as Rank) // every process hears every SAFE/UNSAFE
+                .map(|i| (i, match reply {
+                    Handled::Next(MessageKind::Unsafe) => Message::Unsafe { op: id, up_to, ts: ts.clone() },
+                    _ => Message::Safe { op: id, up_to, ts: ts.clone() },
+                }))
+                .collect(),
+            _ => Vec::new(),
+        };
+        let clock = self.clock(id.queue);
+        Ok(replies.into_iter()
+            .map(|(receiver, reply)| reply.encode(self.index, receiver, &clock))
+            .collect())
+    }
 
-        world.barrier(); // All processes reach the barrier
-        if self.index == op.receiver {
-            return self.handle_queue_op(recv_op);
+    fn send(&mut self, op: QueueOpReq<T>) -> Result<(), QueueError> {
+        self.trace_send(&op);
+        if op.receiver == self.index {
+            self.loopback.push_back(op);
+        } else {
+            self.transport.send(&op)?;
         }
+        Ok(())
+    }
 
-        OpNextAction::default()
+    /// Handles one incoming message if there is one, returns whether there was
+    pub(crate) fn progress(&mut self) -> Result<bool, QueueError> {
+        let op = match self.loopback.pop_front() {
+            Some(op) => op,
+            None => match self.transport.try_receive()? {
+                Some(op) => op,
+                None => return Ok(false),
+            },
+        };
+        self.trace_receive(&op);
+        for reply in self.react(op)? {
+            self.send(reply)?;
+        }
+        Ok(true)
     }
 
-    pub(crate) fn async_send_receive(&mut self, universe: &Universe, op: QueueOpReq) -> OpNextAction {
-        if (op.receiver == op.sender)  && self.index == op.sender {
-            return self.handle_queue_op(op); // dont need to use MPI to send to self
-        } else if op.receiver == op.sender { // do nothing
-            return OpNextAction::default();
+    fn progress_or_yield(&mut self) -> Result<(), QueueError> {
+        if !self.progress()? {
+            thread::yield_now();
         }
+        Ok(())
+    }
 
-        let mut recv_op = QueueOpReq::default();
+    /// Invokes call on queue from this rank alone, the others learn about it from its messages.
+    /// Returns the id to wait on, more ops can be invoked before waiting
+    pub(crate) fn invoke(&mut self, queue: QueueId, call: QueueCall<T>) -> Result<OpId, QueueError> {
+        let (id, messages) = self.start(queue, &call)?;
+        for op in messages {
+            self.send(op)?;
+        }
+        Ok(id)
+    }
 
-        let world = universe.world();
-        mpi::request::scope(|scope| {
-            if self.index == op.sender {
-                let mut sreq = world.process_at_rank(op.receiver)
-                    .immediate_send(scope, &op);
-                loop {
-                    match sreq.test() {
-                        Ok(_) => break,
-                        Err(req) => sreq = req,
-                    }
-                }
-            } else if world.rank() == op.receiver {
-                let rreq = WaitGuard::from(world.process_at_rank(op.sender)
-                    .immediate_receive_into(scope, &mut recv_op));
-                drop(rreq);
+    /// Keeps handling messages until the operation id has finished
+    pub(crate) fn wait(&mut self, id: OpId) -> Result<QueueResult<T>, QueueError> {
+        loop {
+            if let Some(pos) = self.completed.iter().position(|(done, _)| *done == id) {
+                return Ok(self.completed.remove(pos).unwrap().1);
             }
-        });
+            self.progress_or_yield()?;
+        }
+    }
 
-        if self.index == op.receiver {
-            return self.handle_queue_op(recv_op);
-        }
-
-        OpNextAction::default()
-    }
-
-    pub(crate) fn enqueue(&mut self, invoking: Rank, val: u16) {
-        let mut enq_ts = Default::default();
-        if self.index == invoking {
-            enq_ts = self.async_send_receive(self.universe, QueueOpReq{
-                message: ENQ_INVOKE,
-                value: val,
-                sender: invoking,
-                receiver: invoking,
-                timestamp: self.vector_clock,
-            }).ts;
-        }
-
-        for i in 0..NUM_PROCS {
-            if i != invoking as usize {
-                if self.index as usize == i { // receiver
-                    enq_ts = self.async_send_receive(self.universe, QueueOpReq{
-                        message: ENQ_REQ,
-                        value: val,
-                        sender: invoking,
-                        receiver: i as Rank,
-                        timestamp: enq_ts,
-                    }).ts;
-                }else {
-                    self.async_send_receive(self.universe, QueueOpReq{
-                        message: ENQ_REQ,
-                        value: val,
-                        sender: invoking,
-                        receiver: i as Rank,
-                        timestamp: enq_ts,
-                    });
-                }
+    /// Handles everything that already arrived, then tells whether the operation id has finished
+    pub(crate) fn test(&mut self, id: OpId) -> Result<bool, QueueError> {
+        while self.progress()? {}
+        Ok(self.completed.iter().any(|(done, _)| *done == id))
+    }
+
+    /// wait that gives up after timeout, tells whether the operation id has finished
+    pub(crate) fn wait_timeout(&mut self, id: OpId, timeout: Duration) -> Result<bool, QueueError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.completed.iter().any(|(done, _)| *done == id) {
+                return Ok(true);
+            }
+            if Instant::now() >= deadline {
+                return Ok(false);
             }
+            self.progress_or_yield()?;
         }
+    }
 
-        for i in 0..NUM_PROCS {
-            if i != invoking as usize {
-                self.async_send_receive(self.universe, QueueOpReq{
-                    message: ENQ_ACK,
-                    value: val,
-                    sender: i as Rank,
-                    receiver: invoking,
-                    timestamp: enq_ts,
-                });
-            }
+    /// Stops waiting for the operation id. Its messages are out already and every rank built
+    /// its confirmation list from them, so it runs to the end like any other op and the items
+    /// a dequeue gets end up in orphaned. Returns the result if it had finished before
+    pub(crate) fn cancel(&mut self, id: OpId) -> Option<QueueResult<T>> {
+        if let Some(pos) = self.completed.iter().position(|(done, _)| *done == id) {
+            return self.completed.remove(pos).map(|(_, result)| result);
+        }
+        if self.pending.contains_key(&id) {
+            self.cancelled.insert(id);
         }
+        None
     }
 
-    pub(crate) fn dequeue(&mut self, invoking: Rank) -> Option<u16> {
-        let mut message_buffer: u16 = 0;
-        let mut deq_ts = self.async_send_receive(self.universe, QueueOpReq{
-            message: DEQ_INVOKE,
-            value: 0,
-            sender: invoking,
-            receiver: invoking,
-            timestamp: self.vector_clock,
-        }).ts;
+    /// cancel for an op whose handle is gone, a result it already had is orphaned as well
+    pub(crate) fn abandon(&mut self, id: OpId) {
+        if let Some(result) = self.cancel(id) {
+            self.orphan(result);
+        }
+    }
 
+    /// Items dequeues got after they were cancelled, in the order they finished
+    pub(crate) fn drain_orphaned(&mut self) -> Vec<T> {
+        std::mem::take(&mut self.orphaned)
+    }
 
-        for i in 0..NUM_PROCS {
-            if self.index as usize == i {
-                 let res = self.async_send_receive(self.universe, QueueOpReq{
-                    message: DEQ_REQ,
-                    value: 0,
-                    sender: invoking,
-                    receiver: i as Rank,
-                    timestamp: deq_ts,
-                });
-                deq_ts = res.ts;
-                message_buffer = res.message;
+    /// How many items ever reached the local copy of queue
+    pub(crate) fn arrived(&self, queue: QueueId) -> usize {
+        self.queues.get(&queue).map_or(0, |state| state.arrived)
+    }
 
-            } else {
-                self.async_send_receive(self.universe, QueueOpReq{
-                    message: DEQ_REQ,
-                    value: 0,
-                    sender: invoking,
-                    receiver: i as Rank,
-                    timestamp: deq_ts,
-                });
+    /// Dequeues from queue, but waits for an item instead of returning ⊥. Every attempt is an
+    /// ordinary dequeue and one that finds the queue empty changes nothing, so the take
+    /// linearizes at the attempt that got the item and FIFO holds. Between attempts it only
+    /// handles messages until another item reaches the local queue. None once timeout ran out,
+    /// an attempt in flight is finished first so it can run over a bit
+    pub(crate) fn take(&mut self, queue: QueueId, timeout: Option<Duration>) -> Result<Option<T>, QueueError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let arrived = self.arrived(queue);
+            let op = self.invoke(queue, QueueCall::Dequeue)?;
+            if let QueueResult::Dequeued(Some(value)) = self.wait(op)? {
+                return Ok(Some(value));
+            }
+            while self.arrived(queue) == arrived {
+                if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+                    return Ok(None);
+                }
+                self.progress_or_yield()?;
             }
         }
+    }
 
-        let mut ret_val = OpNextAction::default();
-
-        for i in 0..NUM_PROCS {
-            for j in 0..NUM_PROCS {
-                let res = self.async_send_receive(self.universe, QueueOpReq{
-                    message: message_buffer,
-                    value: 0,
-                    sender: i as Rank,
-                    receiver: j as Rank,
-                    timestamp: deq_ts,
-                });
+    /// True once nothing can arrive for us anymore: everyone sent DONE and we have every request
+    /// they made, our ops are finished and every dequeue we heard of got its SAFE/UNSAFE from all
+    /// ranks. Counting requests instead of trusting DONE to come last keeps this right when
+    /// messages get reordered
+    pub(crate) fn finished(&self) -> bool {
+        self.done_from.len() == self.num_procs - 1
+            && self.done_from.iter().all(|(rank, &ops)| self.requests_from.get(rank).copied().unwrap_or(0) == ops)
+            && self.pending.is_empty()
+            && self.loopback.is_empty()
+            && self.seen.values().all(|got| !got.iter().any(|(message, _)| *message == MessageKind::DeqReq))
+    }
 
-                match res.value{
-                    Some(val) => ret_val = res,
-                    _=>{}
-                }
+    /// Tells everyone this rank wont invoke anything else, it still has to keep answering them
+    /// until finished
+    pub(crate) fn announce_done(&mut self) -> Result<(), QueueError> {
+        let clock = self.clock(DEFAULT_QUEUE);
+        for i in 0..self.num_procs as Rank {
+            if i != self.index {
+                let done = Message::Done { ops: self.invoked };
+                self.send(done.encode(self.index, i, &clock))?;
             }
         }
+        Ok(())
+    }
 
-        return ret_val.value;
+    /// Tells everyone this rank wont invoke anything else and keeps answering the others until
+    /// it is safe to stop
+    pub(crate) fn shutdown(&mut self) -> Result<(), QueueError> {
+        self.announce_done()?;
+        while !self.finished() {
+            self.progress_or_yield()?;
+        }
+        Ok(())
     }
 }
 
-impl<'universe> fmt::Debug for Process<'universe> {
+impl<T: Payload, C: Transport<T>> fmt::Debug for Process<T, C> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{{ Process: {}, local_queue: {:?}, ts: {:?}, enq_count: {} }}",
-            self.index, self.local_queue, self.vector_clock, self.enq_count
-        )
+            "{{ Process: {}, ",
+            self.index
+        )?;
+        for (id, state) in &self.queues {
+            let name = state.name.as_deref().unwrap_or("?");
+            write!(f, "queue {:?} ({}): local_queue: {:?}, ts: {:?}, ", name, id, state.local_queue, state.vector_clock)?;
+        }
+        write!(f, "enqueues in flight: {} }}", self.enq_acks.len())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use super::*;
+    use crate::util::transport::ChannelTransport;
+
+    fn run(process: &mut Process<u32, ChannelTransport<u32>>, call: QueueCall<u32>) -> QueueResult<u32> {
+        let id = process.invoke(DEFAULT_QUEUE, call).unwrap();
+        process.wait(id).unwrap()
+    }
+
+    /// Three ranks as threads: rank 0 enqueues and dequeues its own items back in order, rank 1
+    /// only dequeues once rank 0 is through and gets what is left, rank 2 just answers
+    #[test]
+    fn fifo_over_channels() {
+        let (through, wait_for_rank_0) = mpsc::channel();
+        let mut wait_for_rank_0 = Some(wait_for_rank_0);
+        let mut mesh = ChannelTransport::<u32>::mesh(3);
+        let threads: Vec<_> = mesh.drain(..).map(|transport| {
+            let through = through.clone();
+            let wait = if transport.rank() == 1 { wait_for_rank_0.take() } else { None };
+            thread::spawn(move || {
+                let mut process = Process::initialize(transport);
+                process.verbose = false;
+                let mut results = Vec::new();
+                match process.index {
+                    0 => {
+                        for value in 1..=3 {
+                            assert_eq!(run(&mut process, QueueCall::Enqueue(value)), QueueResult::Enqueued);
+                        }
+                        results.push(run(&mut process, QueueCall::Dequeue));
+                        results.push(run(&mut process, QueueCall::Dequeue));
+                        through.send(()).unwrap();
+                    }
+                    1 => {
+                        let wait = wait.unwrap();
+                        while wait.try_recv().is_err() {
+                            process.progress().unwrap();
+                            thread::yield_now();
+                        }
+                        results.push(run(&mut process, QueueCall::Dequeue));
+                        results.push(run(&mut process, QueueCall::Dequeue));
+                    }
+                    _ => {}
+                }
+                process.shutdown().unwrap();
+                results
+            })
+        }).collect();
+
+        let results: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+        assert_eq!(results[0], vec![QueueResult::Dequeued(Some(1)), QueueResult::Dequeued(Some(2))]);
+        assert_eq!(results[1], vec![QueueResult::Dequeued(Some(3)), QueueResult::Dequeued(None)]);
+    }
+
+    /// Runs call from invoker to the end with every message handed over twice
+    fn run_duplicated(processes: &mut [Process<u32, ChannelTransport<u32>>], invoker: Rank, call: QueueCall<u32>) -> QueueResult<u32> {
+        let (id, messages) = processes[invoker as usize].start(DEFAULT_QUEUE, &call).unwrap();
+        let mut messages = VecDeque::from(messages);
+        while let Some(op) = messages.pop_front() {
+            let receiver = &mut processes[op.receiver as usize];
+            messages.extend(receiver.react(op.clone()).unwrap());
+            let reported = receiver.duplicates.len();
+            assert!(receiver.react(op).unwrap().is_empty(), "a duplicate got an answer");
+            assert_eq!(receiver.duplicates.len(), reported + 1);
+        }
+        let invoker = &mut processes[invoker as usize];
+        let (done, result) = invoker.completed.pop_front().expect("op didnt finish");
+        assert_eq!(done, id);
+        result
+    }
+
+    /// Every message arrives twice, the copies are dropped and reported and nothing about the
+    /// ops is kept once they are through
+    #[test]
+    fn drops_duplicates() {
+        let mut processes: Vec<_> = ChannelTransport::<u32>::mesh(3).into_iter()
+            .map(|transport| {
+                let mut process = Process::initialize(transport);
+                process.verbose = false;
+                process.on_duplicate = DuplicatePolicy::Report;
+                process
+            })
+            .collect();
+
+        assert_eq!(run_duplicated(&mut processes, 0, QueueCall::Enqueue(1)), QueueResult::Enqueued);
+        assert_eq!(run_duplicated(&mut processes, 2, QueueCall::Enqueue(2)), QueueResult::Enqueued);
+        assert_eq!(run_duplicated(&mut processes, 1, QueueCall::Dequeue), QueueResult::Dequeued(Some(1)));
+        for process in &processes {
+            let values: Vec<_> = process.queues[&DEFAULT_QUEUE].local_queue.iter().map(|(_, value, _)| *value).collect();
+            assert_eq!(values, vec![2], "rank {}", process.index);
+            assert!(process.seen.is_empty(), "rank {} still tracks {:?}", process.index, process.seen.keys());
+        }
+    }
+
+    /// An empty batch would reach the other ranks as an enqueue of 0 or a plain dequeue, it
+    /// doesnt start and nothing goes out
+    #[test]
+    fn empty_batches_dont_start() {
+        let mut process = Process::initialize(ChannelTransport::<u32>::mesh(2).remove(0));
+        process.verbose = false;
+        for call in [QueueCall::EnqueueMany(Vec::new()), QueueCall::DequeueUpTo(0)] {
+            assert_eq!(process.start(DEFAULT_QUEUE, &call).unwrap_err(), QueueError::EmptyBatch);
+        }
+        assert_eq!(process.invoked, 0);
+        assert!(process.pending.is_empty());
+    }
+
+    /// costarring and liquid have the same FNV-1a, opening the second fails and the first stays
+    /// open under its name. Opening a name twice is fine
+    #[test]
+    fn clashing_names_dont_open() {
+        let mut process = Process::initialize(ChannelTransport::<u32>::mesh(1).remove(0));
+        let id = process.open("costarring").unwrap();
+        assert_eq!(process.open("costarring").unwrap(), id);
+        assert_eq!(process.open("liquid").unwrap_err(), QueueError::QueueIdClash {
+            opened: "costarring".to_string(),
+            name: "liquid".to_string(),
+            id,
+        });
+        assert_eq!(process.queues[&id].name.as_deref(), Some("costarring"));
+    }
+
+    /// Whoever drives the process hears about a message that doesnt decode, it isnt dropped
+    #[test]
+    fn garbled_messages_are_errors() {
+        let mut process = Process::initialize(ChannelTransport::<u32>::mesh(2).remove(0));
+        process.verbose = false;
+        let op = OpId { queue: DEFAULT_QUEUE, rank: 1, seq: 1 };
+        let request = Message::EnqReq { op, values: vec![5], ts: VectorClock(vec![0, 1]) }.encode(1, 0, &VectorClock::new(2));
+
+        let stretched = QueueOpReq { timestamp: VectorClock(vec![0, 1, 0]), ..request.clone() };
+        assert_eq!(process.react(stretched).unwrap_err(), ProtocolError::BadTimestamp { len: 3, expected: 2 });
+        let unknown_sender = QueueOpReq { sender: 2, ..request.clone() };
+        assert_eq!(process.react(unknown_sender).unwrap_err(), ProtocolError::BadRank { field: "sender", rank: 2 });
+        assert!(process.queues[&DEFAULT_QUEUE].local_queue.is_empty());
+        assert!(process.react(request).is_ok());
     }
-}
\ No newline at end of file
+
+    /// Rank 2 was started for a world of 4, it finds out before reading anything and the
+    /// others find out from its HELLO
+    #[test]
+    fn handshake_catches_another_world_size() {
+        let threads: Vec<_> = ChannelTransport::<u32>::mesh(3).into_iter()
+            .map(|transport| thread::spawn(move || {
+                let mut process = Process::initialize(transport);
+                let expected = if process.index == 2 { Some(4) } else { None };
+                process.handshake(expected)
+            }))
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), Err(QueueError::WorldSizeMismatch { expected: 4, actual: 3 }));
+        }
+    }
+
+    /// Rank 1 says HELLO with another protocol version or payload type
+    #[test]
+    fn handshake_catches_another_version_or_payload() {
+        let hellos = [
+            (PROTOCOL_VERSION + 1, u32::fingerprint(), QueueError::VersionMismatch { rank: 1, ours: PROTOCOL_VERSION, theirs: PROTOCOL_VERSION + 1 }),
+            (PROTOCOL_VERSION, String::fingerprint(), QueueError::PayloadMismatch { rank: 1, ours: u32::fingerprint(), theirs: String::fingerprint() }),
+        ];
+        for (version, payload, error) in hellos {
+            let mut mesh = ChannelTransport::<u32>::mesh(2);
+            let mut peer = mesh.pop().unwrap();
+            let hello = Message::Hello { procs: 2, version, payload }.encode(1, 0, &VectorClock::new(2));
+            peer.send(&hello).unwrap();
+
+            let mut process = Process::initialize(mesh.pop().unwrap());
+            assert_eq!(process.handshake(None), Err(error));
+        }
+    }
+}
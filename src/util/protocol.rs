@@ -0,0 +1,269 @@
+use std::fmt;
+use mpi::Rank;
+use crate::util::message_structs::{ OpId, QueueId, QueueOpReq, RankInfo, VectorClock };
+use crate::util::payload::Payload;
+
+/// Bumped whenever the wire format or the meaning of a message changes, ranks check they agree
+/// on it in the startup handshake
+pub const PROTOCOL_VERSION: i32 = 4;
+
+/// Message kinds and the codes they have on the wire. 7, 9 and 10 were kinds that never left a
+/// process, they stay unused so old and new ranks dont read a code two ways
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum MessageKind {
+    #[default]
+    EnqReq = 0, // what a zeroed header says
+    DeqReq = 1,
+    EnqAck = 2,
+    Unsafe = 3,
+    Safe = 4,
+    EnqInvoke = 5,
+    DeqInvoke = 6,
+    Done = 8, // sender wont invoke anything anymore
+    Hello = 11, // first message to every rank, what the sender runs with
+}
+
+impl MessageKind {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            MessageKind::EnqReq => "ENQ_REQ",
+            MessageKind::DeqReq => "DEQ_REQ",
+            MessageKind::EnqAck => "ENQ_ACK",
+            MessageKind::Unsafe => "UNSAFE",
+            MessageKind::Safe => "SAFE",
+            MessageKind::EnqInvoke => "ENQ_INVOKE",
+            MessageKind::DeqInvoke => "DEQ_INVOKE",
+            MessageKind::Done => "DONE",
+            MessageKind::Hello => "HELLO",
+        }
+    }
+}
+
+impl fmt::Display for MessageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl From<MessageKind> for u16 {
+    fn from(kind: MessageKind) -> u16 {
+        kind as u16
+    }
+}
+
+impl TryFrom<u16> for MessageKind {
+    type Error = ProtocolError;
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        Ok(match code {
+            0 => MessageKind::EnqReq,
+            1 => MessageKind::DeqReq,
+            2 => MessageKind::EnqAck,
+            3 => MessageKind::Unsafe,
+            4 => MessageKind::Safe,
+            5 => MessageKind::EnqInvoke,
+            6 => MessageKind::DeqInvoke,
+            8 => MessageKind::Done,
+            11 => MessageKind::Hello,
+            _ => return Err(ProtocolError::UnknownCode(code)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    UnknownCode(u16), // not a message kind at all
+    BadRank { field: &'static str, rank: Rank }, // outside the world
+    BadTimestamp { len: usize, expected: usize }, // clock with the wrong number of entries
+    MissingValue(MessageKind), // a reply that should carry a value came back without one
+    OutOfPlace(MessageKind), // HELLO after the handshake or anything else during it
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnknownCode(code) => write!(f, "unknown message code {}", code),
+            ProtocolError::BadRank { field, rank } => write!(f, "{} {} is not a rank", field, rank),
+            ProtocolError::BadTimestamp { len, expected } => write!(f, "timestamp has {} entries, not {}", len, expected),
+            ProtocolError::MissingValue(kind) => write!(f, "{} came back without a value", kind),
+            ProtocolError::OutOfPlace(kind) => write!(f, "{} came outside its place in the handshake", kind),
+        }
+    }
+}
+impl std::error::Error for ProtocolError {}
+
+/// One protocol message with what it needs, checked. Invokes come from the local client,
+/// everything else from another rank or ourselves. An enqueue carries one value or a whole
+/// batch, up_to is how many items a batch dequeue takes, 0 for a plain one
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message<T: Payload> {
+    EnqInvoke { queue: QueueId, values: Vec<T> },
+    DeqInvoke { queue: QueueId, up_to: u32 },
+    EnqReq { op: OpId, values: Vec<T>, ts: VectorClock },
+    EnqAck { op: OpId, ts: VectorClock }, // the invoker kept the values, an ack doesnt send them back
+    DeqReq { op: OpId, up_to: u32, ts: VectorClock },
+    Safe { op: OpId, up_to: u32, ts: VectorClock },
+    Unsafe { op: OpId, up_to: u32, ts: VectorClock },
+    Done { ops: i32 }, // how many ops the sender invoked, on all queues together
+    Hello { procs: i32, version: i32, payload: u32 }, // expected world size, PROTOCOL_VERSION, Payload::fingerprint
+}
+
+impl<T: Payload> Message<T> {
+    /// Checks a message as it came in, num_procs is the world it should fit in
+    pub(crate) fn decode(op: &QueueOpReq<T>, num_procs: usize) -> Result<Self, ProtocolError> {
+        for (field, rank) in [("sender", op.sender), ("receiver", op.receiver)] {
+            if rank < 0 || rank as usize >= num_procs {
+                return Err(ProtocolError::BadRank { field, rank });
+            }
+        }
+        if op.message == MessageKind::Hello { // has no op, and its clock may not fit a world we dont agree on yet
+            let RankInfo { procs, version, payload, .. } = op.info;
+            return Ok(Message::Hello { procs, version, payload });
+        }
+        if op.op.rank < 0 || op.op.rank as usize >= num_procs {
+            return Err(ProtocolError::BadRank { field: "op rank", rank: op.op.rank });
+        }
+        let ts = op.timestamp.clone();
+        if ts.0.len() != num_procs {
+            return Err(ProtocolError::BadTimestamp { len: ts.0.len(), expected: num_procs });
+        }
+
+        let id = op.op;
+        let up_to = op.up_to;
+        let values = || if op.batch.is_empty() { vec![op.value.clone()] } else { op.batch.clone() };
+        Ok(match op.message {
+            MessageKind::EnqInvoke => Message::EnqInvoke { queue: id.queue, values: values() },
+            MessageKind::DeqInvoke => Message::DeqInvoke { queue: id.queue, up_to },
+            MessageKind::EnqReq => Message::EnqReq { op: id, values: values(), ts },
+            MessageKind::EnqAck => Message::EnqAck { op: id, ts },
+            MessageKind::DeqReq => Message::DeqReq { op: id, up_to, ts },
+            MessageKind::Safe => Message::Safe { op: id, up_to, ts },
+            MessageKind::Unsafe => Message::Unsafe { op: id, up_to, ts },
+            MessageKind::Done => Message::Done { ops: op.info.ops },
+            MessageKind::Hello => unreachable!("HELLO is decoded before the op and clock checks"),
+        })
+    }
+
+    pub(crate) fn kind(&self) -> MessageKind {
+        match self {
+            Message::EnqInvoke { .. } => MessageKind::EnqInvoke,
+            Message::DeqInvoke { .. } => MessageKind::DeqInvoke,
+            Message::EnqReq { .. } => MessageKind::EnqReq,
+            Message::EnqAck { .. } => MessageKind::EnqAck,
+            Message::DeqReq { .. } => MessageKind::DeqReq,
+            Message::Safe { .. } => MessageKind::Safe,
+            Message::Unsafe { .. } => MessageKind::Unsafe,
+            Message::Done { .. } => MessageKind::Done,
+            Message::Hello { .. } => MessageKind::Hello,
+        }
+    }
+
+    /// Wire form of the message from sender to receiver. Invokes dont have an op or ts yet,
+    /// they get the queue's clock the invoker has now and are never sent anyway. A single value
+    /// goes as the payload, more go in batch. DONE and HELLO are about no op, what they say
+    /// goes in info and their clock is zero
+    pub(crate) fn encode(self, sender: Rank, receiver: Rank, clock: &VectorClock) -> QueueOpReq<T> {
+        let kind = self.kind();
+        let invoke = |queue| OpId { queue, ..OpId::default() };
+        let mut info = RankInfo::default();
+        let (op, values, up_to, timestamp) = match self {
+            Message::EnqInvoke { queue, values } => (invoke(queue), values, 0, clock.clone()),
+            Message::DeqInvoke { queue, up_to } => (invoke(queue), Vec::new(), up_to, clock.clone()),
+            Message::EnqReq { op, values, ts } => (op, values, 0, ts),
+            Message::EnqAck { op, ts } => (op, Vec::new(), 0, ts),
+            Message::DeqReq { op, up_to, ts } | Message::Safe { op, up_to, ts } | Message::Unsafe { op, up_to, ts } => {
+                (op, Vec::new(), up_to, ts) // payload is a placeholder
+            }
+            Message::Done { ops } => {
+                info.ops = ops;
+                (OpId::default(), Vec::new(), 0, VectorClock::new(clock.0.len()))
+            }
+            Message::Hello { procs, version, payload } => {
+                info = RankInfo { procs, version, payload, ops: 0 };
+                (OpId::default(), Vec::new(), 0, VectorClock::new(clock.0.len()))
+            }
+        };
+        let (value, batch) = match <[T; 1]>::try_from(values) {
+            Ok([value]) => (value, Vec::new()),
+            Err(values) if values.is_empty() => (T::default(), Vec::new()),
+            Err(batch) => (T::default(), batch),
+        };
+        QueueOpReq { message: kind, value, sender, receiver, op, timestamp, batch, up_to, info }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::message_structs::DEFAULT_QUEUE;
+
+    fn op(rank: Rank, seq: i32) -> OpId {
+        OpId { queue: DEFAULT_QUEUE, rank, seq }
+    }
+
+    #[test]
+    fn codes_round_trip() {
+        for code in (0..=6u16).chain([8, 11]) {
+            let kind = MessageKind::try_from(code).unwrap();
+            assert_eq!(u16::from(kind), code);
+        }
+        for code in [7, 9, 10, 12, 99, u16::MAX] {
+            assert_eq!(MessageKind::try_from(code), Err(ProtocolError::UnknownCode(code)));
+        }
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let ts = VectorClock(vec![2, 0, 5]);
+        let messages = [
+            Message::EnqReq { op: op(0, 2), values: vec![7u16], ts: ts.clone() },
+            Message::EnqReq { op: op(0, 2), values: vec![7, 8, 9], ts: ts.clone() },
+            Message::EnqAck { op: op(2, 5), ts: ts.clone() },
+            Message::DeqReq { op: op(2, 5), up_to: 0, ts: ts.clone() },
+            Message::Safe { op: op(2, 5), up_to: 3, ts: ts.clone() },
+            Message::Unsafe { op: op(1, 1), up_to: 0, ts: ts.clone() },
+            Message::Done { ops: 4 },
+            Message::Hello { procs: 3, version: PROTOCOL_VERSION, payload: 0xF00D },
+        ];
+        for message in messages {
+            let wire = message.clone().encode(1, 2, &ts);
+            assert_eq!(Message::decode(&wire, 3), Ok(message));
+        }
+    }
+
+    #[test]
+    fn rejects_ranks_outside_the_world() {
+        let ts = VectorClock::new(3);
+        let good = Message::EnqAck::<u16> { op: op(2, 1), ts: ts.clone() }.encode(1, 2, &ts);
+        let cases = [
+            (QueueOpReq { sender: -1, ..good.clone() }, ProtocolError::BadRank { field: "sender", rank: -1 }),
+            (QueueOpReq { sender: 3, ..good.clone() }, ProtocolError::BadRank { field: "sender", rank: 3 }),
+            (QueueOpReq { receiver: 7, ..good.clone() }, ProtocolError::BadRank { field: "receiver", rank: 7 }),
+            (QueueOpReq { op: op(3, 1), ..good.clone() }, ProtocolError::BadRank { field: "op rank", rank: 3 }),
+        ];
+        for (bad, error) in cases {
+            assert_eq!(Message::decode(&bad, 3), Err(error));
+        }
+    }
+
+    #[test]
+    fn rejects_timestamps_of_another_world() {
+        let ts = VectorClock::new(2);
+        let wire = Message::DeqReq::<u16> { op: op(0, 1), up_to: 0, ts: ts.clone() }.encode(0, 1, &ts);
+        assert_eq!(Message::decode(&wire, 3), Err(ProtocolError::BadTimestamp { len: 2, expected: 3 }));
+
+        // HELLO is how ranks find out they disagree on the world, it has to get through
+        let hello = Message::<u16>::Hello { procs: 2, version: PROTOCOL_VERSION, payload: 1 }.encode(0, 1, &ts);
+        assert_eq!(Message::decode(&hello, 3), Ok(Message::Hello { procs: 2, version: PROTOCOL_VERSION, payload: 1 }));
+    }
+
+    /// The codes of what used to be kinds that only existed inside a process dont read as
+    /// anything now
+    #[test]
+    fn rejects_codes_of_in_process_kinds() {
+        for code in [7, 9, 10] {
+            assert_eq!(MessageKind::try_from(code), Err(ProtocolError::UnknownCode(code)));
+        }
+    }
+}
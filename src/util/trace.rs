@@ -0,0 +1,266 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use mpi::Rank;
+use serde::{Deserialize, Serialize};
+use crate::util::message_structs::{ OpId, QueueOpReq, VectorClock };
+use crate::util::payload::Payload;
+use crate::util::protocol::MessageKind;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceEventKind {
+    Invoke,
+    Send,
+    Receive,
+    Response,
+}
+
+/// One line of a trace file. Field names and meanings are the file format, dont rename them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TraceEvent {
+    pub rank: Rank, // rank that recorded the event
+    pub event: TraceEventKind,
+    pub op: Option<OpId>, // always set now, traces from before messages carried it can have null
+    pub kind: String, // ENQ_INVOKE / DEQ_INVOKE for invoke and response, the message name otherwise
+    pub peer: Option<Rank>, // receiver of a send, sender of a receive
+    pub value: Option<String>, // Debug form of the value, null for ⊥ and messages without one
+    pub ts: Option<Vec<i32>>, // timestamp the message carries
+    pub clock: Vec<i32>, // recording rank's clock, before a receive is merged in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch: Option<Vec<String>>, // values of a batch enqueue or what a batch dequeue got, value is null then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub up_to: Option<u32>, // items a batch dequeue asked for
+}
+
+/// The output and why it stopped, if it did
+type TraceOut = (Box<dyn Write + Send>, Option<io::Error>);
+
+/// Writes the events of one rank as JSON lines. Clones share the same output. Recording cant
+/// fail the protocol step it happens in, so a write that fails stops the trace and the error
+/// waits in take_error
+#[derive(Clone)]
+pub struct TraceRecorder {
+    rank: Rank,
+    out: Arc<Mutex<TraceOut>>,
+}
+
+pub(crate) fn trace_file(dir: &Path, rank: Rank) -> PathBuf {
+    dir.join(format!("rank-{}.jsonl", rank))
+}
+
+impl TraceRecorder {
+    pub(crate) fn new(rank: Rank, out: Box<dyn Write + Send>) -> Self {
+        Self {
+            rank,
+            out: Arc::new(Mutex::new((out, None))),
+        }
+    }
+
+    /// Why the trace stopped, if it did. Everything recorded after that is missing
+    pub(crate) fn take_error(&self) -> Option<io::Error> {
+        self.out.lock().unwrap_or_else(PoisonError::into_inner).1.take()
+    }
+
+    /// Traces to dir/rank-<rank>.jsonl, creating dir if needed
+    pub(crate) fn to_dir(rank: Rank, dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let file = File::create(trace_file(dir, rank))?;
+        Ok(Self::new(rank, Box::new(BufWriter::new(file))))
+    }
+
+    pub(crate) fn invoke<T: Payload>(&mut self, message: MessageKind, op: OpId, value: Option<&T>, clock: &VectorClock) {
+        self.write(TraceEvent {
+            rank: self.rank,
+            event: TraceEventKind::Invoke,
+            op: Some(op),
+            kind: message.to_string(),
+            peer: None,
+            value: value.map(|value| format!("{:?}", value)),
+            ts: None,
+            clock: clock.0.clone(),
+            batch: None,
+            up_to: None,
+        });
+    }
+
+    /// invoke of a batch op, an enqueue of values or a dequeue of up to up_to items
+    pub(crate) fn invoke_batch<T: Payload>(&mut self, message: MessageKind, op: OpId, values: &[T], up_to: u32, clock: &VectorClock) {
+        self.write(TraceEvent {
+            rank: self.rank,
+            event: TraceEventKind::Invoke,
+            op: Some(op),
+            kind: message.to_string(),
+            peer: None,
+            value: None,
+            ts: None,
+            clock: clock.0.clone(),
+            batch: (message == MessageKind::EnqInvoke).then(|| debug_all(values)),
+            up_to: (up_to > 0).then_some(up_to),
+        });
+    }
+
+    pub(crate) fn send<T: Payload>(&mut self, op: &QueueOpReq<T>, clock: &VectorClock) {
+        let event = self.message_event(TraceEventKind::Send, op.receiver, op, clock);
+        self.write(event);
+    }
+
+    pub(crate) fn receive<T: Payload>(&mut self, op: &QueueOpReq<T>, clock: &VectorClock) {
+        let event = self.message_event(TraceEventKind::Receive, op.sender, op, clock);
+        self.write(event);
+    }
+
+    /// Records the response if the finished operation was invoked here, other ranks finishing
+    /// someone elses dequeue isnt a response
+    pub(crate) fn response<T: Payload>(&mut self, message: MessageKind, op: OpId, op_ts: &VectorClock, value: Option<&T>, clock: &VectorClock) {
+        if op.rank != self.rank {
+            return;
+        }
+        self.write(TraceEvent {
+            rank: self.rank,
+            event: TraceEventKind::Response,
+            op: Some(op),
+            kind: message.to_string(),
+            peer: None,
+            value: value.map(|value| format!("{:?}", value)),
+            ts: Some(op_ts.0.clone()),
+            clock: clock.0.clone(),
+            batch: None,
+            up_to: None,
+        });
+    }
+
+    /// response of an enqueue, a batch one if it had more than one value
+    pub(crate) fn enqueued<T: Payload>(&mut self, op: OpId, op_ts: &VectorClock, values: &[T], clock: &VectorClock) {
+        match values {
+            [value] => self.response(MessageKind::EnqInvoke, op, op_ts, Some(value), clock),
+            values => self.response_batch(MessageKind::EnqInvoke, op, op_ts, values, clock),
+        }
+    }
+
+    /// response of a batch op with every value it enqueued or got
+    pub(crate) fn response_batch<T: Payload>(&mut self, message: MessageKind, op: OpId, op_ts: &VectorClock, values: &[T], clock: &VectorClock) {
+        if op.rank != self.rank {
+            return;
+        }
+        self.write(TraceEvent {
+            rank: self.rank,
+            event: TraceEventKind::Response,
+            op: Some(op),
+            kind: message.to_string(),
+            peer: None,
+            value: None,
+            ts: Some(op_ts.0.clone()),
+            clock: clock.0.clone(),
+            batch: Some(debug_all(values)),
+            up_to: None,
+        });
+    }
+
+    fn message_event<T: Payload>(&self, event: TraceEventKind, peer: Rank, op: &QueueOpReq<T>, clock: &VectorClock) -> TraceEvent {
+        TraceEvent {
+            rank: self.rank,
+            event,
+            op: Some(op.op),
+            kind: op.message.to_string(),
+            peer: Some(peer),
+            value: match op.message {
+                MessageKind::EnqReq => Some(format!("{:?}", op.value)),
+                _ => None, // ENQ_ACK, DEQ_REQ, SAFE and UNSAFE carry a placeholder
+            },
+            ts: Some(op.timestamp.0.clone()),
+            clock: clock.0.clone(),
+            batch: None,
+            up_to: None,
+        }
+    }
+
+    fn write(&mut self, event: TraceEvent) {
+        let mut guard = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+        let (out, error) = &mut *guard;
+        let written = serde_json::to_writer(&mut *out, &event).map_err(io::Error::from)
+            .and_then(|()| out.write_all(b"\n"))
+            .and_then(|()| out.flush()); // keep what we have if the run hangs or dies
+        if let Err(e) = written {
+            *out = Box::new(io::sink()); // a trace with a hole in it would check as something that didnt happen
+            *error = Some(e);
+        }
+    }
+}
+
+fn debug_all<T: Payload>(values: &[T]) -> Vec<String> {
+    values.iter().map(|value| format!("{:?}", value)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::message_structs::DEFAULT_QUEUE;
+    use crate::util::protocol::Message;
+
+    /// Collects what the recorder writes, clones share it
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// One JSON line per event, a response only for ops invoked on the recording rank
+    #[test]
+    fn records_one_line_per_event() {
+        let shared = Shared::default();
+        let mut recorder = TraceRecorder::new(0, Box::new(shared.clone()));
+        let clock = VectorClock(vec![1, 0]);
+        let ours = OpId { queue: DEFAULT_QUEUE, rank: 0, seq: 1 };
+        let theirs = OpId { queue: DEFAULT_QUEUE, rank: 1, seq: 1 };
+        recorder.invoke(MessageKind::EnqInvoke, ours, Some(&7u32), &clock);
+        recorder.send(&Message::EnqReq { op: ours, values: vec![7u32], ts: clock.clone() }.encode(0, 1, &clock), &clock);
+        recorder.receive(&Message::<u32>::EnqAck { op: ours, ts: clock.clone() }.encode(1, 0, &clock), &clock);
+        recorder.response(MessageKind::EnqInvoke, ours, &clock, Some(&7u32), &clock);
+        recorder.response::<u32>(MessageKind::DeqInvoke, theirs, &clock, None, &clock);
+
+        let written = String::from_utf8(shared.0.lock().unwrap().clone()).unwrap();
+        let events: Vec<TraceEvent> = written.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let kinds: Vec<_> = events.iter().map(|event| (event.event, event.kind.as_str(), event.peer)).collect();
+        assert_eq!(kinds, vec![
+            (TraceEventKind::Invoke, "ENQ_INVOKE", None),
+            (TraceEventKind::Send, "ENQ_REQ", Some(1)),
+            (TraceEventKind::Receive, "ENQ_ACK", Some(1)),
+            (TraceEventKind::Response, "ENQ_INVOKE", None),
+        ]);
+        assert_eq!(events[1].value.as_deref(), Some("7"));
+        assert_eq!(events[2].value, None);
+        assert!(events.iter().all(|event| event.rank == 0 && event.op == Some(ours)));
+    }
+
+    /// A failed write doesnt fail the caller, it stops the trace and shows up once in take_error
+    #[test]
+    fn a_failed_write_stops_the_trace() {
+        let mut recorder = TraceRecorder::new(0, Box::new(Broken));
+        let op = OpId { queue: DEFAULT_QUEUE, rank: 0, seq: 1 };
+        recorder.invoke::<u32>(MessageKind::DeqInvoke, op, None, &VectorClock::new(2));
+        recorder.invoke::<u32>(MessageKind::DeqInvoke, op, None, &VectorClock::new(2));
+        assert_eq!(recorder.clone().take_error().unwrap().to_string(), "disk full");
+        assert!(recorder.take_error().is_none());
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Barrier};
+use mpi::environment::Universe;
+use mpi::Rank;
+use mpi::topology::SimpleCommunicator;
+use mpi::traits::*;
+use mpi::request::{ Request, Scope, WaitGuard };
+use crate::util::message_structs::{ QueueOpHeader, QueueOpReq, VectorClock };
+use crate::util::payload::Payload;
+use crate::util::protocol::ProtocolError;
+
+/// Why a message couldnt go out or come in
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransportError {
+    HungUp(Rank), // the other end is gone, eg its thread finished or panicked
+    Garbled(Rank, ProtocolError), // rank sent something that doesnt read as a message
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::HungUp(rank) => write!(f, "rank {} hung up", rank),
+            TransportError::Garbled(rank, e) => write!(f, "rank {} sent a broken message: {}", rank, e),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransportError::Garbled(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Everything Process needs from the outside world. Sends and receives are point to point
+/// and in order per (sender, receiver) pair, the same guarantee MPI gives us
+pub trait Transport<T: Payload> {
+    fn rank(&self) -> Rank;
+
+    fn size(&self) -> usize;
+
+    /// Sends op to op.receiver, returns once the send is complete
+    fn send(&mut self, op: &QueueOpReq<T>) -> Result<(), TransportError>;
+
+    /// Blocks until the next message from source arrives
+    fn receive(&mut self, source: Rank) -> Result<QueueOpReq<T>, TransportError>;
+
+    /// Next message from any rank if one has arrived, doesnt block
+    fn try_receive(&mut self) -> Result<Option<QueueOpReq<T>>, TransportError>;
+
+    fn barrier(&mut self) -> Result<(), TransportError>;
+}
+
+/// Transport over an MPI communicator, the world unless it is built with with_communicator.
+/// Ranks are the communicator's ranks, so a queue over a split only involves that split
+pub struct MpiTransport<T: Payload, M: Communicator = SimpleCommunicator> {
+    comm: M,
+    inbox: VecDeque<(Rank, Result<QueueOpReq<T>, ProtocolError>)>, // taken off the wire while our own sends were stuck, in arrival order
+}
+
+impl<T: Payload> MpiTransport<T> {
+    pub fn new(universe: &Universe) -> Self {
+        Self::with_communicator(universe.world())
+    }
+}
+
+impl<T: Payload, M: Communicator> MpiTransport<T, M> {
+    /// Transport over comm, eg one from split_by_color or split_by_subgroup_collective. Other
+    /// traffic on comm would get mixed up with the queue's, pass a duplicate if comm is shared
+    pub fn with_communicator(comm: M) -> Self {
+        Self {
+            comm,
+            inbox: VecDeque::new(),
+        }
+    }
+
+    /// Takes the next header, clock and payload from source off the wire, even if the header
+    /// turns out to be garbage, so the stream from source stays in step
+    fn receive_checked(comm: &M, source: Rank) -> Result<QueueOpReq<T>, ProtocolError> {
+        let mut recv_header = QueueOpHeader::default();
+        let mut recv_ts = VectorClock::new(comm.size() as usize);
+        let sender = comm.process_at_rank(source);
+
+        mpi::request::scope(|scope| {
+            let rreq = WaitGuard::from(sender.immediate_receive_into(scope, &mut recv_header));
+            drop(rreq);
+            let rreq = WaitGuard::from(sender.immediate_receive_into(scope, &mut recv_ts.0[..]));
+            drop(rreq);
+        });
+        // payload length depends on T, so let MPI size the buffer
+        let recv_value = sender.receive_vec::<T::Elem>().0;
+        let mut batch = Vec::new();
+        if recv_header.batch > 0 { // the lengths of a batch's values, then all their elements in one go
+            let lengths = sender.receive_vec::<i32>().0;
+            let elems = sender.receive_vec::<T::Elem>().0;
+            let mut start = 0;
+            for length in lengths {
+                let end = (start + length.max(0) as usize).min(elems.len());
+                batch.push(T::from_elems(&elems[start..end]));
+                start = end;
+            }
+        }
+
+        QueueOpReq::from_parts(recv_header, recv_ts, T::from_elems(&recv_value), batch)
+    }
+
+    /// Next message from source we already took off the wire, if there is one
+    fn take_from_inbox(&mut self, source: Rank) -> Option<Result<QueueOpReq<T>, TransportError>> {
+        let pos = self.inbox.iter().position(|(from, _)| *from == source)?;
+        let (_, op) = self.inbox.remove(pos)?;
+        Some(op.map_err(|e| TransportError::Garbled(source, e)))
+    }
+}
+
+/// Tests a send that might still be in flight, true once it is done
+fn sent<'a, D: ?Sized, S: Scope<'a>>(req: &mut Option<Request<'a, D, S>>) -> bool {
+    if let Some(pending) = req.take() {
+        if let Err(pending) = pending.test() {
+            *req = Some(pending);
+            return false;
+        }
+    }
+    true
+}
+
+impl<T: Payload, M: Communicator> Transport<T> for MpiTransport<T, M> {
+    fn rank(&self) -> Rank {
+        self.comm.rank()
+    }
+
+    fn size(&self) -> usize {
+        self.comm.size() as usize
+    }
+
+    /// MPI aborts the whole job on a failed call unless told otherwise, so the MPI side only
+    /// ever returns Ok. Above the eager limit a send only completes once the receiver posts its
+    /// receive, and the receiver might be stuck sending to us, so we keep taking messages off
+    /// the wire into the inbox until ours are through
+    fn send(&mut self, op: &QueueOpReq<T>) -> Result<(), TransportError> {
+        let send_header = op.header();
+        let send_value = op.value.to_elems();
+        let batch: Vec<Vec<T::Elem>> = op.batch.iter().map(Payload::to_elems).collect();
+        let batch_lengths: Vec<i32> = batch.iter().map(|elems| elems.len() as i32).collect();
+        let batch_elems: Vec<T::Elem> = batch.concat();
+        let comm = &self.comm;
+        let inbox = &mut self.inbox;
+        let receiver = comm.process_at_rank(op.receiver);
+
+        mpi::request::scope(|scope| {
+            // posted in the order they are received in: header, clock, payload, then the batch
+            let mut header = Some(receiver.immediate_send(scope, &send_header));
+            let mut clock = Some(receiver.immediate_send(scope, &op.timestamp.0[..]));
+            let mut value = Some(receiver.immediate_send(scope, &send_value[..]));
+            let (mut lengths, mut elems) = (None, None);
+            if !op.batch.is_empty() {
+                lengths = Some(receiver.immediate_send(scope, &batch_lengths[..]));
+                elems = Some(receiver.immediate_send(scope, &batch_elems[..]));
+            }
+            loop {
+                let done = [sent(&mut header), sent(&mut clock), sent(&mut value), sent(&mut lengths), sent(&mut elems)];
+                if done.iter().all(|d| *d) {
+                    break;
+                }
+                if let Some(status) = comm.any_process().immediate_probe() {
+                    let source = status.source_rank();
+                    inbox.push_back((source, Self::receive_checked(comm, source)));
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn receive(&mut self, source: Rank) -> Result<QueueOpReq<T>, TransportError> {
+        if let Some(op) = self.take_from_inbox(source) {
+            return op;
+        }
+        Self::receive_checked(&self.comm, source).map_err(|e| TransportError::Garbled(source, e))
+    }
+
+    fn try_receive(&mut self) -> Result<Option<QueueOpReq<T>>, TransportError> {
+        if let Some((source, op)) = self.inbox.pop_front() {
+            return op.map(Some).map_err(|e| TransportError::Garbled(source, e));
+        }
+        // a header is always followed by its clock and payload from the same sender, so once
+        // the header is there the rest can be received from that rank
+        let Some(status) = self.comm.any_process().immediate_probe() else { return Ok(None) };
+        let source = status.source_rank();
+        Self::receive_checked(&self.comm, source).map(Some).map_err(|e| TransportError::Garbled(source, e))
+    }
+
+    fn barrier(&mut self) -> Result<(), TransportError> {
+        self.comm.barrier();
+        Ok(())
+    }
+}
+
+/// In memory transport for running every rank as a thread in one process, no MPI needed
+pub struct ChannelTransport<T: Payload> {
+    rank: Rank,
+    senders: Vec<Sender<QueueOpReq<T>>>, // senders[j] goes to rank j
+    receivers: Vec<Receiver<QueueOpReq<T>>>, // receivers[i] comes from rank i
+    next_source: usize, // where try_receive starts looking, so no sender gets starved
+    barrier: Arc<Barrier>,
+}
+
+impl<T: Payload> ChannelTransport<T> {
+    /// Builds a fully connected set of num_procs transports, hand element i to the thread
+    /// running rank i
+    pub fn mesh(num_procs: usize) -> Vec<Self> {
+        let barrier = Arc::new(Barrier::new(num_procs));
+        let mut senders: Vec<Vec<Sender<QueueOpReq<T>>>> = (0..num_procs).map(|_| Vec::new()).collect();
+        let mut receivers: Vec<Vec<Receiver<QueueOpReq<T>>>> = (0..num_procs).map(|_| Vec::new()).collect();
+
+        for from in 0..num_procs {
+            for to in 0..num_procs {
+                let (tx, rx) = channel();
+                senders[from].push(tx);
+                receivers[to].push(rx); // pushed in order of from
+            }
+        }
+
+        senders.into_iter().zip(receivers).enumerate()
+            .map(|(rank, (senders, receivers))| ChannelTransport {
+                rank: rank as Rank,
+                senders,
+                receivers,
+                next_source: 0,
+                barrier: Arc::clone(&barrier),
+            })
+            .collect()
+    }
+}
+
+impl<T: Payload> Transport<T> for ChannelTransport<T> {
+    fn rank(&self) -> Rank {
+        self.rank
+    }
+
+    fn size(&self) -> usize {
+        self.senders.len()
+    }
+
+    fn send(&mut self, op: &QueueOpReq<T>) -> Result<(), TransportError> {
+        self.senders[op.receiver as usize].send(op.clone())
+            .map_err(|_| TransportError::HungUp(op.receiver))
+    }
+
+    fn receive(&mut self, source: Rank) -> Result<QueueOpReq<T>, TransportError> {
+        self.receivers[source as usize].recv()
+            .map_err(|_| TransportError::HungUp(source))
+    }
+
+    /// A rank that hung up after its last message isnt an error here, ranks that are done drop
+    /// their transport while the others may still be finishing
+    fn try_receive(&mut self) -> Result<Option<QueueOpReq<T>>, TransportError> {
+        let size = self.receivers.len();
+        for i in 0..size {
+            let source = (self.next_source + i) % size;
+            match self.receivers[source].try_recv() {
+                Ok(op) => {
+                    self.next_source = (source + 1) % size;
+                    return Ok(Some(op));
+                }
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => {}
+            }
+        }
+        Ok(None)
+    }
+
+    fn barrier(&mut self) -> Result<(), TransportError> {
+        self.barrier.wait();
+        Ok(())
+    }
+}